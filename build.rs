@@ -21,22 +21,49 @@ fn main() {
         .nth(3) // Go up to target/{target}/{profile}
         .unwrap();
 
+    // Only the DLL gets the architecture specific name, test executables must keep their own output path
     match target_arch.as_str() {
         "x86_64" => {
             let dll_path = target_dir.join("win_sdr_thumbs_x64.dll");
-            println!("cargo:rustc-link-arg=/OUT:{}", dll_path.display());
+            println!("cargo:rustc-link-arg-cdylib=/OUT:{}", dll_path.display());
         },
         "x86" => {
             let dll_path = target_dir.join("win_sdr_thumbs_x86.dll");
-            println!("cargo:rustc-link-arg=/OUT:{}", dll_path.display());
+            println!("cargo:rustc-link-arg-cdylib=/OUT:{}", dll_path.display());
         },
         "aarch64" => {
             let dll_path = target_dir.join("win_sdr_thumbs_arm64.dll");
-            println!("cargo:rustc-link-arg=/OUT:{}", dll_path.display());
+            println!("cargo:rustc-link-arg-cdylib=/OUT:{}", dll_path.display());
         },
         _ => {}
     }
 
+    // Build time for the get_version_info export, as UTC so it doesn't depend on the build machine
+    println!("cargo:rustc-env=WIN_SDR_THUMBS_BUILD_TIME={}", utc_timestamp());
+
     // println!("cargo:warning=Target arch: {}", target_arch);
     // println!("cargo:warning=Target directory: {}", target_dir.display());
 }
+
+// Formats the current time as "YYYY-MM-DD HH:MM UTC", without pulling in a date crate
+fn utc_timestamp() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let minutes_of_day = (seconds % 86400) / 60;
+
+    // Days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, minutes_of_day / 60, minutes_of_day % 60)
+}
@@ -0,0 +1,109 @@
+// Registers or unregisters the thumbnail DLL without regsvr32, always loading the DLL that matches
+// this tool's own architecture (the names match the ones build.rs gives the DLL).
+//
+// Usage: win_sdr_thumbs_register [--install | --uninstall] [--user] [path to dll]
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use windows::{
+    core::{s, HRESULT, HSTRING, PCSTR},
+    Win32::Foundation::FreeLibrary,
+    Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryW},
+};
+
+type RegistrationFn = unsafe extern "system" fn() -> HRESULT;
+
+#[cfg(target_arch = "x86_64")]
+const DLL_NAME: &str = "win_sdr_thumbs_x64.dll";
+#[cfg(target_arch = "x86")]
+const DLL_NAME: &str = "win_sdr_thumbs_x86.dll";
+#[cfg(target_arch = "aarch64")]
+const DLL_NAME: &str = "win_sdr_thumbs_arm64.dll";
+
+fn main() -> ExitCode {
+    let mut uninstall = false;
+    let mut per_user = false;
+    let mut dll_path: Option<PathBuf> = None;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--install" => uninstall = false,
+            "--uninstall" => uninstall = true,
+            "--user" => per_user = true,
+            "--help" | "-h" => {
+                print_usage();
+                return ExitCode::SUCCESS;
+            }
+            other if !other.starts_with("--") && dll_path.is_none() => dll_path = Some(PathBuf::from(other)),
+            other => {
+                eprintln!("Unknown argument '{}'", other);
+                print_usage();
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    // By default use the DLL next to this tool
+    let dll_path = match dll_path {
+        Some(path) => path,
+        None => match std::env::current_exe() {
+            Ok(exe_path) => exe_path.with_file_name(DLL_NAME),
+            Err(e) => {
+                eprintln!("Could not locate this tool's folder: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let export_name: PCSTR = match (uninstall, per_user) {
+        (false, false) => s!("DllRegisterServer"),
+        (true, false) => s!("DllUnregisterServer"),
+        (false, true) => s!("DllRegisterServerUser"),
+        (true, true) => s!("DllUnregisterServerUser"),
+    };
+    let export_label = unsafe { export_name.to_string() }.unwrap_or_default();
+
+    let module = match unsafe { LoadLibraryW(&HSTRING::from(dll_path.as_os_str())) } {
+        Ok(module) => module,
+        Err(e) => {
+            eprintln!("Could not load {}: {}", dll_path.display(), e.message());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match unsafe { GetProcAddress(module, export_name) } {
+        Some(function) => {
+            let function: RegistrationFn = unsafe { std::mem::transmute(function) };
+            Ok(unsafe { function() })
+        }
+        None => Err(()),
+    };
+    let _ = unsafe { FreeLibrary(module) };
+
+    match result {
+        Ok(hr) if hr.is_ok() => {
+            println!("{} succeeded for {}", export_label, dll_path.display());
+            ExitCode::SUCCESS
+        }
+        Ok(hr) => {
+            // Machine-wide registration needs an elevated prompt, E_FAIL usually means access was denied
+            eprintln!("{} failed with HRESULT 0x{:08X}", export_label, hr.0 as u32);
+            if !per_user {
+                eprintln!("Registering for all users requires an administrator prompt, or use --user");
+            }
+            ExitCode::FAILURE
+        }
+        Err(()) => {
+            eprintln!("{} does not export {}", dll_path.display(), export_label);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_usage() {
+    eprintln!("Usage: win_sdr_thumbs_register [--install | --uninstall] [--user] [path to dll]");
+    eprintln!("  --install    Register the thumbnail and preview handlers (default)");
+    eprintln!("  --uninstall  Remove the registration");
+    eprintln!("  --user       Only for the current user, doesn't need administrator rights");
+    eprintln!("Without a path, {} next to this tool is used.", DLL_NAME);
+}
@@ -0,0 +1,111 @@
+// Renders SDR files to PNG without Explorer or any GUI, e.g. to check a folder of recordings
+// or to reproduce a rendering problem from the command line.
+//
+// Usage: sdr2png <input file or folder> <output file or folder> [size]
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_MULTITHREADED};
+
+use win_sdr_thumbs::{render_sdr_file_to_png_bytes, SDR_FILE_EXTENSIONS};
+
+const DEFAULT_SIZE: u32 = 256;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("Usage: sdr2png <input file or folder> <output file or folder> [size, default {}]", DEFAULT_SIZE);
+        return ExitCode::from(2);
+    }
+
+    let input_path = PathBuf::from(&args[1]);
+    let output_path = PathBuf::from(&args[2]);
+    let size = match args.get(3).map(|s| s.parse::<u32>()) {
+        None => DEFAULT_SIZE,
+        Some(Ok(size)) if size > 0 => size,
+        Some(_) => {
+            eprintln!("Invalid size '{}', expected a positive number", args[3]);
+            return ExitCode::from(2);
+        }
+    };
+
+    // Collect (input, output) pairs, a folder renders every file with a supported extension into the output folder
+    let jobs: Vec<(PathBuf, PathBuf)> = if input_path.is_dir() {
+        if let Err(e) = std::fs::create_dir_all(&output_path) {
+            eprintln!("Could not create output folder {}: {}", output_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+        let entries = match std::fs::read_dir(&input_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Could not read input folder {}: {}", input_path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut jobs: Vec<(PathBuf, PathBuf)> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_sdr_file(path))
+            .map(|path| {
+                let mut output_name = path.file_name().unwrap_or_default().to_os_string();
+                output_name.push(".png");
+                let output_file = output_path.join(output_name);
+                (path, output_file)
+            })
+            .collect();
+        jobs.sort();
+        jobs
+    } else if output_path.is_dir() {
+        // A single file into an existing folder keeps its name, like the folder case
+        let mut output_name = input_path.file_name().unwrap_or_default().to_os_string();
+        output_name.push(".png");
+        let output_file = output_path.join(output_name);
+        vec![(input_path, output_file)]
+    } else {
+        vec![(input_path, output_path)]
+    };
+
+    if jobs.is_empty() {
+        eprintln!("No SDR files found");
+        return ExitCode::FAILURE;
+    }
+
+    // WIC needs COM, which Explorer would normally have set up for us
+    if let Err(e) = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.ok() {
+        eprintln!("CoInitializeEx failed: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let mut failures = 0;
+    for (input_file, output_file) in &jobs {
+        match render_file(input_file, output_file, size) {
+            Ok(()) => println!("{} -> {}", input_file.display(), output_file.display()),
+            Err(message) => {
+                eprintln!("{}: {}", input_file.display(), message);
+                failures += 1;
+            }
+        }
+    }
+
+    unsafe { CoUninitialize() };
+
+    if failures > 0 {
+        eprintln!("{} of {} files failed", failures, jobs.len());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn is_sdr_file(path: &Path) -> bool {
+    let Some(extension) = path.extension() else {
+        return false;
+    };
+    let extension = format!(".{}", extension.to_string_lossy());
+    SDR_FILE_EXTENSIONS.iter().any(|known| known.eq_ignore_ascii_case(&extension))
+}
+
+fn render_file(input_file: &Path, output_file: &Path, size: u32) -> Result<(), String> {
+    // Reads the file like the thumbnail provider does, the path also decides whether the badge is drawn
+    let png_bytes = render_sdr_file_to_png_bytes(input_file, size, size)
+        .map_err(|e| format!("failed: {} ({:?})", e.message(), e.code()))?;
+    std::fs::write(output_file, png_bytes).map_err(|e| format!("could not write {}: {}", output_file.display(), e))
+}
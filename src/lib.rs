@@ -1,20 +1,24 @@
 use std::{
     ffi::OsStr,
     // fs::OpenOptions,
-    io::Write,
+    io::{Read, Write},
     os::windows::prelude::OsStrExt,
     panic::{catch_unwind, AssertUnwindSafe},
     sync::{
         atomic::{
+            AtomicBool,
             AtomicPtr,
             AtomicU32,
+            AtomicU64,
             Ordering
         },
         Arc,
         Mutex,
-        OnceLock
+        OnceLock,
+        RwLock
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 use windows::{
@@ -33,12 +37,36 @@ use windows::{
                 RegCreateKeyExW,
                 RegSetValueExW,
             },
-            SystemInformation::GetLocalTime
+            SystemInformation::GetLocalTime,
+            Diagnostics::Debug::OutputDebugStringW,
         },
+        UI::WindowsAndMessaging::{
+            CreateWindowExW,
+            DefWindowProcW,
+            DestroyWindow,
+            GetPropW,
+            LoadCursorW,
+            MoveWindow,
+            RegisterClassW,
+            RemovePropW,
+            SetParent,
+            SetPropW,
+            UnregisterClassW,
+            IDC_ARROW,
+            MSG,
+            WINDOW_EX_STYLE,
+            WM_ERASEBKGND,
+            WM_PAINT,
+            WNDCLASSW,
+            WS_CHILD,
+            WS_CLIPSIBLINGS,
+            WS_VISIBLE,
+        },
+        UI::Input::KeyboardAndMouse,
         UI::Shell::{
             self,
             SHGetKnownFolderPath,
-            FOLDERID_Desktop
+            FOLDERID_LocalAppData
         },
         Globalization::{GetTimeFormatEx, TIME_FORMAT_FLAGS},
     },
@@ -53,6 +81,34 @@ unsafe extern "C" {
 // This is the ONLY definition you need. It works for both 32-bit and 64-bit.
 const WRITE_FLAGS: REG_SAM_FLAGS = KEY_WRITE;
 
+// 101 MiB default max file size, applies to the stream, item and file initialization paths. Can be changed via registry.
+const DEFAULT_MAX_FILE_MB: u32 = 101;
+// Upper bound for the registry override, keeps the whole file comfortably addressable in a 32-bit host process
+const MAX_FILE_MB_CEILING: u32 = 1024;
+const ERROR_FILE_TOO_LARGE: WIN32_ERROR = WIN32_ERROR(223u32);
+
+// Upper bound for the requested thumbnail width and height, matches the largest texture size hardware commonly supports.
+// This is also the default, so large preview pane or 8K sizes render as before; the registry can only lower it.
+const MAX_DIMENSION_CEILING: u32 = 16384;
+
+// Sizes Explorer keeps in its thumbnail cache (thumbcache_16.db ... thumbcache_1024.db).
+// With rounding enabled, a requested size is rounded up to the next entry:
+//   1-16 -> 16, 17-32 -> 32, 33-48 -> 48, 49-96 -> 96, 97-256 -> 256, 257-768 -> 768, 769-1024 -> 1024
+// Anything above 1024 is rendered as requested.
+const STANDARD_THUMBNAIL_SIZES: [u32; 7] = [16, 32, 48, 96, 256, 768, 1024];
+
+// Upper bound for the thumbnail padding, leaves at least a fifth of the thumbnail for the content
+const MAX_PADDING_PERCENT: u32 = 40;
+
+// How long GetThumbnail waits for a render before giving up, can be changed via registry (0 waits forever).
+// Off by default, the watchdog costs a thread per thumbnail and is only worth it for hosts that stall on bad files.
+const DEFAULT_RENDER_TIMEOUT_MS: u32 = 0;
+
+// Log files are rolled over once they exceed this size, can be changed via registry (in MiB)
+const DEFAULT_LOG_MAX_SIZE_MB: u32 = 5;
+// Number of rolled over log files to keep next to the current one (.1 is the newest)
+const LOG_ROTATION_COUNT: u32 = 2;
+
 // =================================================================
 //                  FFI Panic Safety Macro
 // =================================================================
@@ -70,7 +126,7 @@ macro_rules! ffi_guard {
                 //RESOURCES.with(|resources| {
                 //    resources.borrow_mut().take();
                 //});
-                //log_message("A PANIC occurred in FFI function.");
+                //log_error!("A PANIC occurred in FFI function.");
                 Err(E_FAIL.into())
             }
         }
@@ -85,7 +141,7 @@ macro_rules! ffi_guard {
                 //RESOURCES.with(|resources| {
                 //    resources.borrow_mut().take();
                 //});
-                //log_message("A PANIC occurred in FFI function.");
+                //log_error!("A PANIC occurred in FFI function.");
                 E_FAIL
             }
         }
@@ -100,11 +156,42 @@ macro_rules! ffi_guard {
                 //RESOURCES.with(|resources| {
                 //    resources.borrow_mut().take();
                 //});
-                //log_message("A PANIC occurred in FFI function.");
+                //log_error!("A PANIC occurred in FFI function.");
                 false.into()
             }
         }
     }};
+
+    // For functions that return a count, a panic reports zero
+    (u32, $body:expr) => {{
+        let result = catch_unwind(AssertUnwindSafe(|| $body));
+        match result {
+            Ok(count) => count,
+            Err(_) => 0,
+        }
+    }};
+}
+
+// =================================================================
+//                      Logging Macros
+// =================================================================
+
+/// Logging macros taking `format!` arguments, one per LogLevel.
+/// They check the configured level first, so disabled messages cost no formatting.
+macro_rules! log_error {
+    ($($arg:tt)*) => { log_at_level(LogLevel::Error, || format!($($arg)*)) };
+}
+macro_rules! log_warn {
+    ($($arg:tt)*) => { log_at_level(LogLevel::Warn, || format!($($arg)*)) };
+}
+macro_rules! log_info {
+    ($($arg:tt)*) => { log_at_level(LogLevel::Info, || format!($($arg)*)) };
+}
+macro_rules! log_debug {
+    ($($arg:tt)*) => { log_at_level(LogLevel::Debug, || format!($($arg)*)) };
+}
+macro_rules! log_trace {
+    ($($arg:tt)*) => { log_at_level(LogLevel::Trace, || format!($($arg)*)) };
 }
 
 // RAII wrapper for HBITMAP - automatically calls DeleteObject when dropped
@@ -125,6 +212,9 @@ impl HBitmapGuard {
     }
 }
 
+// GDI bitmaps aren't tied to the thread that created them, so the guard can move a finished bitmap between threads
+unsafe impl Send for HBitmapGuard {}
+
 impl Drop for HBitmapGuard {
     fn drop(&mut self) {
         // Only delete if the handle is not null/invalid (i.e., it hasn't been released).
@@ -149,8 +239,30 @@ impl Drop for CoTaskMemFreeGuard {
     }
 }
 
+/// Renders the SDR data into a new 32bpp top-down DIB section with straight alpha.
+/// If the data can't be rendered this returns the error, it never substitutes a fallback image.
+/// Substituting a placeholder is up to the shell integration, see `GetThumbnail`.
 pub fn render_sdr_to_hbitmap(sdr_data: &[u8], sdr_name: &str, requested_width: u32, requested_height: u32) -> Result<Gdi::HBITMAP> {
-    log_message(&format!("render_sdr_to_hbitmap: Starting render for {}x{} size, {} bytes of data", requested_width, requested_height, sdr_data.len()));
+    let request = RenderRequest { source_path: None, width: requested_width, height: requested_height, cache_only: false };
+    create_thumbnail_hbitmap(sdr_data, sdr_name, &request)
+}
+
+/// How a thumbnail should be rendered, besides the data itself
+#[derive(Clone, Copy)]
+struct RenderRequest<'a> {
+    /// The file's full path if known, only files in the badge folder get the badge
+    source_path: Option<&'a Path>,
+    width: u32,
+    height: u32,
+    /// Only consult the disk cache, a miss fails with E_PENDING instead of rendering, see IThumbnailSettings::SetContext
+    cache_only: bool,
+}
+
+/// Implementation of render_sdr_to_hbitmap
+fn create_thumbnail_hbitmap(sdr_data: &[u8], sdr_name: &str, request: &RenderRequest) -> Result<Gdi::HBITMAP> {
+    let (requested_width, requested_height) = (request.width, request.height);
+    log_debug!("render_sdr_to_hbitmap: Starting render for {}x{} size, {} bytes of data", requested_width, requested_height, sdr_data.len());
+    validate_dimensions(requested_width, requested_height)?;
 
     // 7. Create the final GDI HBITMAP
     // This creates a separate GDI bitmap with its own memory buffer
@@ -159,146 +271,1420 @@ pub fn render_sdr_to_hbitmap(sdr_data: &[u8], sdr_name: &str, requested_width: u
         biPlanes: 1, biBitCount: 32, biCompression: Gdi::BI_RGB.0 as u32, ..Default::default()
     }, ..Default::default() };
 
-    let mut dib_data: *mut std::ffi::c_void = std::ptr::null_mut();
-    let hbitmap_handle: Gdi::HBITMAP = unsafe {
-        Gdi::CreateDIBSection(None, &bmi, Gdi::DIB_RGB_COLORS, &mut dib_data, None, 0)
-    }?;
-    let hbitmap_guard = HBitmapGuard::new(hbitmap_handle);
+    let mut dib_data: *mut std::ffi::c_void = std::ptr::null_mut();
+    let hbitmap_handle: Gdi::HBITMAP = unsafe {
+        Gdi::CreateDIBSection(None, &bmi, Gdi::DIB_RGB_COLORS, &mut dib_data, None, 0)
+    }.map_err(|e| {
+        log_error!("render_sdr_to_hbitmap: Error - CreateDIBSection failed: {:?}", e);
+        RenderError::BitmapCreation(Some(e))
+    })?;
+    let hbitmap_guard = HBitmapGuard::new(hbitmap_handle);
+
+    // 8. Render the SDR data straight into the GDI HBITMAP buffer
+    if dib_data.is_null() {
+        log_error!("render_sdr_to_hbitmap: Error - CreateDIBSection returned no pixel buffer");
+        return Err(RenderError::BitmapCreation(None).into());
+    }
+    let render_start = std::time::Instant::now();
+    let config = config();
+    {
+        // Safety: The bitmap bit values are aligned on doubleword boundaries
+        let pixels = unsafe { std::slice::from_raw_parts_mut(dib_data as *mut u32, requested_width as usize * requested_height as usize) };
+        compose_thumbnail_pixels(sdr_data, sdr_name, request, pixels, &config)?;
+    } // The pixel slice ends here, GDI writes to the same memory below
+
+    if config.enable_debug_overlay {
+        draw_debug_overlay(hbitmap_handle, dib_data as *mut u32, requested_width, requested_height, render_start.elapsed());
+    }
+
+    log_debug!("render_sdr_to_hbitmap: Successfully completed rendering");
+    Ok(hbitmap_guard.release())
+}
+
+/// Renders the SDR data and applies the configured padding and badge, everything a thumbnail gets except the GDI-drawn
+/// debug overlay. Shared by the thumbnail provider and the PNG functions, so both produce the same image.
+/// `pixels` must start out transparent.
+fn compose_thumbnail_pixels(sdr_data: &[u8], sdr_name: &str, request: &RenderRequest, pixels: &mut [u32], config: &Config) -> std::result::Result<(), RenderError> {
+    let RenderRequest { source_path, width, height, cache_only } = *request;
+    if config.padding_percent > 0 {
+        render_sdr_pixels_padded(sdr_data, sdr_name, width, height, pixels, cache_only, config.padding_percent)?;
+    } else {
+        render_sdr_pixels(sdr_data, sdr_name, width, height, pixels, cache_only)?;
+    }
+
+    // Applied after the disk cache, so cached thumbnails stay unbadged and a changed badge shows up right away
+    if let (Some(badge_path), Some(badge_folder), Some(source_path)) = (&config.badge_image_path, &config.badge_folder, source_path) {
+        if is_in_folder(source_path, badge_folder) {
+            apply_badge_overlay(badge_path, pixels, width, height);
+        }
+    }
+    Ok(())
+}
+
+/// Renders the SDR data into the middle of `pixels`, leaving a transparent margin of `padding_percent` of the size on
+/// each side, so thumbnails don't touch their neighbors in Explorer. `pixels` must already be transparent (a fresh DIB
+/// section is zeroed). The inset content is rendered at its own smaller size rather than scaled down.
+fn render_sdr_pixels_padded(sdr_data: &[u8], sdr_name: &str, width: u32, height: u32, pixels: &mut [u32], cache_only: bool, padding_percent: u32) -> std::result::Result<(), RenderError> {
+    let (padding_x, padding_y) = (width * padding_percent / 100, height * padding_percent / 100);
+    let (inner_width, inner_height) = ((width - 2 * padding_x).max(1), (height - 2 * padding_y).max(1));
+
+    let mut inner_pixels = vec![0u32; inner_width as usize * inner_height as usize];
+    render_sdr_pixels(sdr_data, sdr_name, inner_width, inner_height, &mut inner_pixels, cache_only)?;
+
+    for (inner_y, inner_row) in inner_pixels.chunks_exact(inner_width as usize).enumerate() {
+        let row_start = (padding_y as usize + inner_y) * width as usize + padding_x as usize;
+        pixels[row_start..row_start + inner_width as usize].copy_from_slice(inner_row);
+    }
+    Ok(())
+}
+
+/// Renders the SDR data into a bitmap owned by the caller, for hosts that pool their own bitmaps.
+/// The bitmap must be a DIB section (CreateDIBSection) with 32 bits per pixel, BI_RGB and a negative height (top-down).
+/// Pixels are written as BGRA with straight alpha, the render size is the bitmap's size. Any other bitmap is rejected
+/// with E_INVALIDARG. The whole bitmap is overwritten, with the same padding, badge and debug overlay settings as
+/// `render_sdr_to_hbitmap` (the badge needs a path, so it is never drawn here). Ownership stays with the caller and the
+/// bitmap is not deleted. Only with the debug overlay enabled it is briefly selected into a memory DC of our own, so it
+/// must not be selected into one of the caller's at that point.
+pub fn render_sdr_into_hbitmap(sdr_data: &[u8], sdr_name: &str, target_hbitmap: Gdi::HBITMAP) -> Result<()> {
+    let mut dib_section = Gdi::DIBSECTION::default();
+    let object_size = unsafe {
+        Gdi::GetObjectW(Gdi::HGDIOBJ(target_hbitmap.0), std::mem::size_of::<Gdi::DIBSECTION>() as i32, Some(&mut dib_section as *mut _ as *mut std::ffi::c_void))
+    };
+
+    // GetObjectW only fills the full DIBSECTION for DIB sections, device-dependent bitmaps return just a BITMAP
+    let bitmap = &dib_section.dsBm;
+    let header = &dib_section.dsBmih;
+    let invalid_reason = if object_size != std::mem::size_of::<Gdi::DIBSECTION>() as i32 || bitmap.bmBits.is_null() {
+        Some("Target bitmap is not a DIB section")
+    } else if bitmap.bmBitsPixel != 32 || header.biCompression != Gdi::BI_RGB.0 as u32 {
+        Some("Target bitmap is not 32bpp BI_RGB")
+    } else if header.biHeight >= 0 {
+        Some("Target bitmap is not top-down")
+    } else if bitmap.bmWidthBytes != bitmap.bmWidth * 4 {
+        Some("Target bitmap has an unexpected stride")
+    } else {
+        None
+    };
+    if let Some(reason) = invalid_reason {
+        log_error!("render_sdr_into_hbitmap: Error - {}", reason);
+        return Err(RenderError::InvalidTargetBitmap(reason).into());
+    }
+
+    let width = bitmap.bmWidth as u32;
+    let height = bitmap.bmHeight.unsigned_abs();
+    log_debug!("render_sdr_into_hbitmap: Starting render into caller bitmap of {}x{} size, {} bytes of data", width, height, sdr_data.len());
+    validate_dimensions(width, height)?;
+
+    // Make sure pending GDI drawing on the bitmap is done before we write to its memory
+    let _ = unsafe { Gdi::GdiFlush() };
+    let dib_bits = bitmap.bmBits as *mut u32;
+    let render_start = std::time::Instant::now();
+    let config = config();
+    {
+        let pixels = unsafe { std::slice::from_raw_parts_mut(dib_bits, width as usize * height as usize) };
+        // The padding is only drawn over, a pooled bitmap may still hold the previous thumbnail
+        pixels.fill(0);
+        let request = RenderRequest { source_path: None, width, height, cache_only: false };
+        compose_thumbnail_pixels(sdr_data, sdr_name, &request, pixels, &config)?;
+    } // The pixel slice ends here, GDI writes to the same memory below
+
+    if config.enable_debug_overlay {
+        draw_debug_overlay(target_hbitmap, dib_bits, width, height, render_start.elapsed());
+    }
+    Ok(())
+}
+
+/// Renders the SDR data and encodes the result as a PNG file image via WIC.
+/// This is meant for tools and tests that want to inspect or save the output rather than hand a GDI handle to the shell.
+/// The image matches the thumbnail, including the configured padding and badge, only the debug overlay is left out.
+/// Like `render_sdr_to_hbitmap`, a render failure is returned as an error rather than a fallback image.
+/// COM must already be initialized on the calling thread. Without a path the badge is never drawn, see
+/// `render_sdr_file_to_png_bytes`.
+pub fn render_sdr_to_png_bytes(sdr_data: &[u8], sdr_name: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+    render_to_png_bytes(sdr_data, sdr_name, None, width, height)
+}
+
+/// Reads an SDR file, with the same size cap as the shell integration, and renders it like `render_sdr_to_png_bytes`.
+/// Knowing the path, the badge is drawn just as Explorer would for this file.
+pub fn render_sdr_file_to_png_bytes(file_path: &Path, width: u32, height: u32) -> Result<Vec<u8>> {
+    let file_data = read_file_data(file_path)?;
+    // The badge folder is an absolute path, so compare against the absolute path of a relative input
+    let source_path = std::path::absolute(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+    render_to_png_bytes(&file_data.stream_bytes, &file_data.stream_name, Some(&source_path), width, height)
+}
+
+/// Implementation of render_sdr_to_png_bytes and render_sdr_file_to_png_bytes
+fn render_to_png_bytes(sdr_data: &[u8], sdr_name: &str, source_path: Option<&Path>, width: u32, height: u32) -> Result<Vec<u8>> {
+    log_debug!("render_sdr_to_png_bytes: Starting render for {}x{} size, {} bytes of data", width, height, sdr_data.len());
+    validate_dimensions(width, height)?;
+
+    let mut pixels = vec![0u32; width as usize * height as usize];
+    let request = RenderRequest { source_path, width, height, cache_only: false };
+    compose_thumbnail_pixels(sdr_data, sdr_name, &request, &mut pixels, &config())?;
+    let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+
+    unsafe {
+        let factory: Graphics::Imaging::IWICImagingFactory = Com::CoCreateInstance(&Graphics::Imaging::CLSID_WICImagingFactory, None, Com::CLSCTX_INPROC_SERVER)?;
+        let stream: Com::IStream = Shell::SHCreateMemStream(None).ok_or_else(|| Error::new(E_OUTOFMEMORY, "SHCreateMemStream failed"))?;
+
+        let encoder = factory.CreateEncoder(&Graphics::Imaging::GUID_ContainerFormatPng, None)?;
+        encoder.Initialize(&stream, Graphics::Imaging::WICBitmapEncoderNoCache)?;
+
+        let mut frame: Option<Graphics::Imaging::IWICBitmapFrameEncode> = None;
+        let mut encoder_options = None;
+        encoder.CreateNewFrame(&mut frame, &mut encoder_options)?;
+        let frame = frame.ok_or_else(|| Error::new(E_FAIL, "CreateNewFrame returned no frame"))?;
+        frame.Initialize(None)?;
+        frame.SetSize(width, height)?;
+
+        // The encoder may pick a different format than requested, we only hand it straight BGRA
+        let mut pixel_format = Graphics::Imaging::GUID_WICPixelFormat32bppBGRA;
+        frame.SetPixelFormat(&mut pixel_format)?;
+        if pixel_format != Graphics::Imaging::GUID_WICPixelFormat32bppBGRA {
+            log_error!("render_sdr_to_png_bytes: Error - PNG encoder does not accept 32bpp BGRA");
+            return Err(Error::new(WINCODEC_ERR_UNSUPPORTEDPIXELFORMAT, "PNG encoder does not accept 32bpp BGRA"));
+        }
+
+        frame.WritePixels(height, width * 4, &pixel_bytes)?;
+        frame.Commit()?;
+        encoder.Commit()?;
+
+        // Read the encoded PNG back out of the memory stream
+        let mut statstg = Default::default();
+        stream.Stat(&mut statstg, Com::STATFLAG_NONAME)?;
+        stream.Seek(0, Com::STREAM_SEEK_SET, None)?;
+
+        let mut png_bytes = vec![0u8; statstg.cbSize as usize];
+        let mut bytes_read: u32 = 0;
+        stream.Read(png_bytes.as_mut_ptr() as *mut core::ffi::c_void, png_bytes.len() as u32, Some(&mut bytes_read)).ok()?;
+        png_bytes.truncate(bytes_read as usize);
+
+        log_debug!("render_sdr_to_png_bytes: Encoded {} bytes of PNG", png_bytes.len());
+        Ok(png_bytes)
+    }
+}
+
+/// Draws a small diagnostic label (renderer, size and render time) into the bottom left corner of a rendered bitmap,
+/// so a screenshot of a misbehaving thumbnail shows the key facts. Only used when win_sdr_thumbs_debug_overlay is set.
+/// `dib_bits` is the bitmap's own pixel memory. GDI draws into it, so no slice over it may be alive during this call.
+fn draw_debug_overlay(hbitmap: Gdi::HBITMAP, dib_bits: *mut u32, width: u32, height: u32, elapsed: std::time::Duration) {
+    let mut label: Vec<u16> = format!("CPU {}x{} {} ms", width, height, elapsed.as_millis()).encode_utf16().collect();
+
+    let text_rect = unsafe {
+        let memory_dc = Gdi::CreateCompatibleDC(None);
+        if memory_dc.is_invalid() {
+            log_warn!("draw_debug_overlay: CreateCompatibleDC failed, skipping overlay");
+            return;
+        }
+        let previous_bitmap = Gdi::SelectObject(memory_dc, Gdi::HGDIOBJ(hbitmap.0));
+        let previous_font = Gdi::SelectObject(memory_dc, Gdi::GetStockObject(Gdi::DEFAULT_GUI_FONT));
+        Gdi::SetTextColor(memory_dc, COLORREF(0x00FFFFFF));
+        Gdi::SetBkColor(memory_dc, COLORREF(0x00000000));
+        Gdi::SetBkMode(memory_dc, Gdi::OPAQUE);
+
+        // Measure the label first, then place it in the bottom left corner
+        let mut text_rect = RECT::default();
+        Gdi::DrawTextW(memory_dc, &mut label, &mut text_rect, Gdi::DT_CALCRECT | Gdi::DT_SINGLELINE | Gdi::DT_NOPREFIX);
+        let text_rect = RECT {
+            left: 0,
+            top: (height as i32 - text_rect.bottom).max(0),
+            right: text_rect.right.min(width as i32),
+            bottom: height as i32,
+        };
+        let mut draw_rect = text_rect;
+        Gdi::DrawTextW(memory_dc, &mut label, &mut draw_rect, Gdi::DT_SINGLELINE | Gdi::DT_NOPREFIX | Gdi::DT_LEFT | Gdi::DT_BOTTOM);
+        let _ = Gdi::GdiFlush();
+
+        Gdi::SelectObject(memory_dc, previous_font);
+        Gdi::SelectObject(memory_dc, previous_bitmap);
+        let _ = Gdi::DeleteDC(memory_dc);
+        text_rect
+    };
+
+    // GDI is done with the bitmap (flushed and deselected), so it is safe to access its memory again
+    let pixels = unsafe { std::slice::from_raw_parts_mut(dib_bits, width as usize * height as usize) };
+
+    // GDI text output clears the alpha channel, the opaque label box is fully covered so make it opaque again
+    for y in text_rect.top as usize..text_rect.bottom as usize {
+        let row = &mut pixels[y * width as usize..][..text_rect.right as usize];
+        for pixel in row.iter_mut() {
+            *pixel |= 0xFF000000;
+        }
+    }
+}
+
+/// The ways the rendering pipeline can fail. Each variant maps to its own HRESULT and message,
+/// so the logs and callers can tell which stage failed instead of seeing a bare E_FAIL.
+#[derive(Debug)]
+enum RenderError {
+    /// The requested size is zero or exceeds the configured maximum dimension
+    InvalidDimensions { width: u32, height: u32, max_dimension: u32 },
+    /// The file name contains a NUL character and can't be passed on to the renderer
+    InvalidName,
+    /// CreateDIBSection failed, or didn't hand out a pixel buffer
+    BitmapCreation(Option<Error>),
+    /// The renderer couldn't draw the data, usually because the file content is not valid SDR data
+    RenderFailed,
+    /// A caller-supplied bitmap doesn't have the required format
+    InvalidTargetBitmap(&'static str),
+    /// Only a cached thumbnail was requested and the disk cache had none
+    NotCached,
+    /// The render didn't finish within the configured render timeout
+    TimedOut { timeout_ms: u32 },
+    /// A render that timed out earlier is still running, or its worker thread couldn't be started
+    RenderBusy,
+}
+
+impl From<RenderError> for Error {
+    fn from(render_error: RenderError) -> Self {
+        match render_error {
+            RenderError::InvalidDimensions { width, height, max_dimension } => Error::new(
+                E_INVALIDARG,
+                format!("Requested size {}x{} is zero or exceeds the maximum dimension {}", width, height, max_dimension),
+            ),
+            RenderError::InvalidName => Error::new(E_INVALIDARG, "File name contains a NUL character"),
+            RenderError::BitmapCreation(Some(error)) => error,
+            RenderError::BitmapCreation(None) => Error::new(E_OUTOFMEMORY, "CreateDIBSection returned no pixel buffer"),
+            RenderError::RenderFailed => Error::new(HRESULT::from_win32(ERROR_INVALID_DATA.0), "SDR renderer could not draw the data"),
+            RenderError::InvalidTargetBitmap(reason) => Error::new(E_INVALIDARG, reason),
+            RenderError::NotCached => Error::new(E_PENDING, "Thumbnail is not cached and a fast result was requested"),
+            RenderError::TimedOut { timeout_ms } => Error::new(HRESULT::from_win32(ERROR_TIMEOUT.0), format!("Render did not finish within {} ms", timeout_ms)),
+            RenderError::RenderBusy => Error::new(E_PENDING, "A timed out render is still running"),
+        }
+    }
+}
+
+/// Rejects zero sizes and sizes above the configured maximum dimension.
+fn validate_dimensions(width: u32, height: u32) -> std::result::Result<(), RenderError> {
+    let max_dimension = config().max_dimension;
+    if width == 0 || height == 0 || width > max_dimension || height > max_dimension {
+        log_warn!("validate_dimensions: Warning - Invalid size {}x{} (max: {})", width, height, max_dimension);
+        return Err(RenderError::InvalidDimensions { width, height, max_dimension });
+    }
+    Ok(())
+}
+
+/// Renders the SDR data into a BGRA pixel buffer, consulting the disk cache first if it is enabled.
+/// This is the common rendering path shared by all public render functions.
+/// Returns an error if the renderer reported that it couldn't draw the data, or with `cache_only` if the cache had no entry.
+fn render_sdr_pixels(sdr_data: &[u8], sdr_name: &str, width: u32, height: u32, pixels: &mut [u32], cache_only: bool) -> std::result::Result<(), RenderError> {
+    let mut timer = PhaseTimer::new();
+    let config = config();
+    let cache_entry = if config.enable_thumbnail_cache {
+        get_cache_dir().map(|cache_dir| {
+            let cache_key = thumbnail_cache_key(sdr_data, sdr_name, width, height);
+            (cache_dir, cache_dir.join(format!("{:016x}.thumb", cache_key)))
+        })
+    } else {
+        None
+    };
+
+    let cache_hit = match &cache_entry {
+        Some((_, cache_path)) => read_cached_thumbnail(cache_path, width, height, pixels),
+        None => false,
+    };
+    timer.mark("cache lookup");
+
+    let rendered = if cache_hit {
+        log_debug!("render_sdr_pixels: Loaded thumbnail from disk cache");
+        true
+    } else if cache_only {
+        log_debug!("render_sdr_pixels: Not in disk cache and only a cached result was requested");
+        return Err(RenderError::NotCached);
+    } else {
+        let file_name = std::ffi::CString::new(sdr_name).map_err(|_| {
+            log_error!("render_sdr_pixels: Error - File name contains a NUL character");
+            RenderError::InvalidName
+        })?;
+        let rendered = unsafe {
+            splt_thumbnail(sdr_data.as_ptr(), sdr_data.len() as u64, file_name.as_ptr(), width, height, pixels.as_mut_ptr())
+        };
+        timer.mark("render");
+        // should use a BGR palette, reorder RGBA for now
+        for x in pixels.iter_mut() {
+            let b = (*x).to_le_bytes();
+            *x = u32::from_le_bytes([b[2], b[1], b[0], b[3]]);
+        }
+        timer.mark("reorder");
+
+        // Only cache successful renders, so a fixed renderer can retry a failed file
+        if let Some((cache_dir, cache_path)) = &cache_entry {
+            if rendered {
+                write_cached_thumbnail(cache_dir, cache_path, width, height, pixels);
+                timer.mark("cache write");
+            }
+        }
+        rendered
+    };
+
+    if !rendered {
+        log_error!("render_sdr_pixels: Error - Renderer failed for {}", sdr_name);
+        return Err(RenderError::RenderFailed);
+    }
+
+    if config.enable_dominant_color_background {
+        apply_dominant_color_background(pixels);
+        timer.mark("background");
+    }
+
+    log_debug!("render_sdr_pixels: Timings for {}x{} on CPU{}: {}", width, height, if cache_hit { " (cache hit)" } else { "" }, timer.summary());
+    Ok(())
+}
+
+/// Collects how long each phase of a render took, for a single summary line in the debug log.
+/// Doesn't even read the clock unless debug logging is on, so it costs nothing in normal operation.
+struct PhaseTimer {
+    start: Option<std::time::Instant>,
+    last: Option<std::time::Instant>,
+    phases: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl PhaseTimer {
+    fn new() -> Self {
+        let start = (config().log_level >= LogLevel::Debug as u32).then(std::time::Instant::now);
+        PhaseTimer { start, last: start, phases: Vec::new() }
+    }
+
+    /// Records the time since the previous mark (or the start) under the given phase name
+    fn mark(&mut self, phase: &'static str) {
+        if let Some(last) = self.last {
+            let now = std::time::Instant::now();
+            self.phases.push((phase, now - last));
+            self.last = Some(now);
+        }
+    }
+
+    fn summary(&self) -> String {
+        let total = self.start.map(|start| start.elapsed()).unwrap_or_default();
+        let mut summary: Vec<String> = self.phases.iter()
+            .map(|(phase, duration)| format!("{} {:.2} ms", phase, duration.as_secs_f64() * 1000.0))
+            .collect();
+        summary.push(format!("total {:.2} ms", total.as_secs_f64() * 1000.0));
+        summary.join(", ")
+    }
+}
+
+/// Fills the transparent parts of a thumbnail with a light tint of its own dominant color.
+/// The dominant color is the alpha-weighted average of all non-transparent pixels. It is
+/// lightened by mixing it 75% towards white, which also desaturates it, so the content stays readable.
+fn apply_dominant_color_background(pixels: &mut [u32]) {
+    let (mut sum_b, mut sum_g, mut sum_r, mut sum_a) = (0u64, 0u64, 0u64, 0u64);
+    for pixel in pixels.iter() {
+        let [b, g, r, a] = pixel.to_le_bytes();
+        sum_b += b as u64 * a as u64;
+        sum_g += g as u64 * a as u64;
+        sum_r += r as u64 * a as u64;
+        sum_a += a as u64;
+    }
+
+    // A fully transparent thumbnail has no color to derive a background from
+    if sum_a == 0 {
+        return;
+    }
+
+    let lighten = |sum: u64| -> u32 {
+        let average = sum / sum_a;
+        (average + (255 - average) * 3 / 4) as u32
+    };
+    fill_transparent_background(pixels, lighten(sum_b), lighten(sum_g), lighten(sum_r));
+}
+
+/// Composites straight alpha BGRA pixels over an opaque background color, leaving every pixel fully opaque.
+fn fill_transparent_background(pixels: &mut [u32], bg_b: u32, bg_g: u32, bg_r: u32) {
+    for pixel in pixels.iter_mut() {
+        let [b, g, r, a] = pixel.to_le_bytes();
+        if a == 255 {
+            continue;
+        }
+        let alpha = a as u32;
+        let blend = |fg: u8, bg: u32| ((fg as u32 * alpha + bg * (255 - alpha) + 127) / 255) as u8;
+        *pixel = u32::from_le_bytes([blend(b, bg_b), blend(g, bg_g), blend(r, bg_r), 255]);
+    }
+}
+
+// =================================================================
+//                      Thumbnail Disk Cache
+// =================================================================
+
+// Total size of the cache directory above which the least recently used entries are evicted
+const MAX_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+// Identifies a cache file, followed by width and height as little-endian u32 and then the raw BGRA pixels
+const CACHE_FILE_MAGIC: &[u8; 4] = b"WST1";
+const CACHE_HEADER_SIZE: usize = CACHE_FILE_MAGIC.len() + 2 * std::mem::size_of::<u32>();
+
+/// FNV-1a hash over the DLL version, the SDR data, the file name and the requested size.
+/// The file name is part of the key because the renderer detects the sample format from the extension.
+/// The version is part of it so an update, which may bring a changed renderer, doesn't keep serving old thumbnails.
+fn thumbnail_cache_key(sdr_data: &[u8], sdr_name: &str, width: u32, height: u32) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    for &byte in version.iter().chain(sdr_data).chain(sdr_name.as_bytes()).chain(&width.to_le_bytes()).chain(&height.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Returns %LOCALAPPDATA%\win_sdr_thumbs\cache, creating it on first use.
+/// Returns None if the folder can't be determined or created, which disables caching.
+fn get_cache_dir() -> Option<&'static PathBuf> {
+    CACHE_DIR_PATH.get_or_init(|| {
+        let mut path = get_known_folder_path(&FOLDERID_LocalAppData)?;
+        path.push("win_sdr_thumbs");
+        path.push("cache");
+
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            log_warn!("get_cache_dir: Failed to create cache directory {}: {}", path.display(), e);
+            return None;
+        }
+        Some(path)
+    }).as_ref()
+}
+
+/// Fills `pixels` from a cache entry. Returns false on a miss or an entry that doesn't match the requested size.
+fn read_cached_thumbnail(cache_path: &Path, width: u32, height: u32, pixels: &mut [u32]) -> bool {
+    let data = match std::fs::read(cache_path) {
+        Ok(data) => data,
+        Err(_) => return false,
+    };
+
+    if data.len() != CACHE_HEADER_SIZE + pixels.len() * 4 || &data[..4] != CACHE_FILE_MAGIC {
+        log_warn!("read_cached_thumbnail: Ignoring malformed cache entry {}", cache_path.display());
+        return false;
+    }
+
+    let cached_width = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let cached_height = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+    if cached_width != width || cached_height != height {
+        return false;
+    }
+
+    for (pixel, bytes) in pixels.iter_mut().zip(data[CACHE_HEADER_SIZE..].chunks_exact(4)) {
+        *pixel = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    // Bump the modified time so the eviction keeps recently used entries
+    if let Ok(file) = std::fs::File::options().write(true).open(cache_path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    true
+}
+
+/// Stores rendered BGRA pixels in the cache, then evicts old entries if the cache grew too large.
+/// The directory is only scanned for the first write in a process and whenever the running size estimate passes
+/// MAX_CACHE_SIZE, not after every write.
+/// Failures are only logged, the cache is an optimization and must never fail a render.
+fn write_cached_thumbnail(cache_dir: &Path, cache_path: &Path, width: u32, height: u32, pixels: &[u32]) {
+    let mut data: Vec<u8> = Vec::with_capacity(CACHE_HEADER_SIZE + pixels.len() * 4);
+    data.extend_from_slice(CACHE_FILE_MAGIC);
+    data.extend_from_slice(&width.to_le_bytes());
+    data.extend_from_slice(&height.to_le_bytes());
+    for pixel in pixels {
+        data.extend_from_slice(&pixel.to_le_bytes());
+    }
+
+    // Write to a temporary file first so a concurrent reader never sees a partial entry. The name is unique per write,
+    // so two threads storing the same entry don't write into the same temporary file.
+    let temp_id = CACHE_TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp_path = cache_path.with_extension(format!("tmp{}_{}", std::process::id(), temp_id));
+    if let Err(e) = std::fs::write(&temp_path, &data).and_then(|_| std::fs::rename(&temp_path, cache_path)) {
+        log_warn!("write_cached_thumbnail: Failed to write cache entry {}: {}", cache_path.display(), e);
+        let _ = std::fs::remove_file(&temp_path);
+        return;
+    }
+
+    // Replacing an existing entry over-counts, which at worst makes the next scan come a bit early
+    let previous_size = CACHE_SIZE_ESTIMATE.fetch_add(data.len() as u64, Ordering::Relaxed);
+    if previous_size == CACHE_SIZE_UNKNOWN || previous_size + data.len() as u64 > MAX_CACHE_SIZE {
+        let total_size = evict_cache_entries(cache_dir).unwrap_or(CACHE_SIZE_UNKNOWN);
+        CACHE_SIZE_ESTIMATE.store(total_size, Ordering::Relaxed);
+    }
+}
+
+/// Deletes the least recently used cache entries until the cache is below MAX_CACHE_SIZE.
+/// Returns the size of the cache afterwards, or None if the directory couldn't be read.
+fn evict_cache_entries(cache_dir: &Path) -> Option<u64> {
+    let entries = std::fs::read_dir(cache_dir).ok()?;
+
+    let mut cache_files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+
+    let mut total_size: u64 = cache_files.iter().map(|(_, size, _)| size).sum();
+    if total_size <= MAX_CACHE_SIZE {
+        return Some(total_size);
+    }
+
+    // Oldest first
+    cache_files.sort_by_key(|(modified, _, _)| *modified);
+    for (_, size, path) in cache_files {
+        if total_size <= MAX_CACHE_SIZE {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total_size -= size;
+        }
+    }
+    log_debug!("evict_cache_entries: Cache trimmed to {} bytes", total_size);
+    Some(total_size)
+}
+
+// =================================================================
+//                 COM Thumbnail Provider Object
+// =================================================================
+
+struct StreamData {
+    stream_bytes: Box<[u8]>,
+    stream_name: String,
+    // Full path of the file, if the shell told us, e.g. to decide whether it gets the badge
+    source_path: Option<PathBuf>,
+}
+
+// Where the SDR data comes from, set by whichever Initialize variant the shell called
+enum ThumbnailSource {
+    // Data already read from an IStream
+    Stream(Arc<StreamData>),
+    // A file path, only read when the thumbnail is requested
+    File(PathBuf),
+}
+
+#[implement(Shell::PropertiesSystem::IInitializeWithStream, Shell::PropertiesSystem::IInitializeWithFile, Shell::IInitializeWithItem, Shell::IThumbnailProvider, Shell::IThumbnailSettings)]
+struct ThumbnailProvider {
+    source: Mutex<Option<ThumbnailSource>>,
+    // Set when the shell passed WTSCF_FAST through IThumbnailSettings::SetContext
+    fast_context: std::sync::atomic::AtomicBool,
+}
+
+impl Default for ThumbnailProvider {
+    fn default() -> Self {
+        dll_add_ref();
+        log_trace!("ThumbnailProvider: Created new instance");
+        Self {
+            source: Mutex::new(None),
+            fast_context: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+}
+
+impl Drop for ThumbnailProvider {
+    fn drop(&mut self) {
+        log_trace!("ThumbnailProvider: Dropping instance");
+        dll_release();
+    }
+}
+
+impl Shell::PropertiesSystem::IInitializeWithStream_Impl for ThumbnailProvider_Impl {
+    #[allow(non_snake_case)]
+    fn Initialize(&self, pstream: Ref<'_, Com::IStream>, _grfmode: u32) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            // log_debug!("Initialize: Starting SDR data loading");
+
+            // Guard against repeated initialization calls
+            if self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?.is_some() {
+                log_warn!("Initialize: Warning - Already initialized");
+                return Err(Error::from(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)));
+            }
+
+            match &*pstream {
+                Some(stream) => {
+                    let stream_data = read_stream_data(stream)?;
+                    *self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))? = Some(ThumbnailSource::Stream(Arc::new(stream_data)));
+
+                    // log_debug!("Initialize: Succeeded.");
+                    Ok(())
+                }
+                None => {
+                    // This case handles if Windows passes a null stream.
+                    log_error!("Initialize: Error - Stream was null.");
+                    Err(E_INVALIDARG.into())
+                }
+            }
+        })
+    }
+}
+
+/// Rejects inputs larger than the configured file size cap with ERROR_FILE_TOO_LARGE.
+/// Every read path checks the reported size up front and the bytes actually read while reading, both through here.
+fn reject_if_too_large(len: u64) -> Result<()> {
+    let max_file_size = config().max_file_size;
+    if len > max_file_size {
+        log_warn!("reject_if_too_large: Warning - File too large: {} bytes (max: {} bytes)", len, max_file_size);
+        return Err(Error::new(HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0), "File exceeds the maximum file size"));
+    }
+    Ok(())
+}
+
+/// Reads a whole stream into memory, respecting the file size cap.
+/// Shared by the IInitializeWithStream implementations of the thumbnail provider and the preview handler.
+fn read_stream_data(stream: &Com::IStream) -> Result<StreamData> {
+    // Fast Fail Check: Ask the stream for its size for a quick rejection.
+    // If the size check fails continue to read the stream in chunks, there is another safety net below.
+    let mut statstg = Default::default();
+    // FIXME: also stat the name to detect the file extension
+    let mut stream_name = String::default();
+    if unsafe { stream.Stat(&mut statstg, Com::STATFLAG_DEFAULT) }.is_ok() {
+        let stream_name_guard = CoTaskMemFreeGuard(statstg.pwcsName);
+        // Streams are allowed to leave the name out even when asked for it
+        if !stream_name_guard.0.is_null() {
+            if let Ok(s) = unsafe { stream_name_guard.0.to_string() } {
+                stream_name = s;
+            }
+        }
+
+        let stream_size = statstg.cbSize;
+        // log_debug!("Initialize: Stream reports size: {} bytes", stream_size);
+        reject_if_too_large(stream_size).inspect_err(|_| log_debug!("Initialize: Rejected {} by its reported size", stream_name))?;
+    } else {
+        log_warn!("Initialize: Warning - Could not get stream size, will read with safety checks");
+    }
+
+    // Do not trust the reported size for allocation.
+    // Start with a default-sized Vec and let it grow.
+    let seq_stream: Com::ISequentialStream = stream.cast()?;
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut chunk: Vec<u8> = vec![0u8; 65536];
+
+    loop {
+        let mut bytes_read: u32 = 0;
+        let hr: HRESULT = unsafe {
+            seq_stream.Read(
+                chunk.as_mut_ptr() as *mut core::ffi::c_void,
+                chunk.len() as u32,
+                Some(&mut bytes_read)
+            )
+        };
+
+        if hr.is_err() || bytes_read == 0 {
+            if hr.is_err() {
+                log_warn!("Initialize: Stream read error: {:?}", hr);
+            }
+            break;
+        }
+
+        // Extra file size safety net protects memory usage in case statstg failed or returned a wrong size.
+        reject_if_too_large(buffer.len() as u64 + bytes_read as u64)?;
+
+        buffer.extend_from_slice(&chunk[..bytes_read as usize]);
+    }
+
+    // log_debug!("Initialize: Successfully loaded {} bytes of SDR data", buffer.len());
+
+    // Convert to a boxed slice to save memory overhead
+    Ok(StreamData { stream_bytes: buffer.into_boxed_slice(), stream_name, source_path: None })
+}
+
+impl Shell::PropertiesSystem::IInitializeWithFile_Impl for ThumbnailProvider_Impl {
+    #[allow(non_snake_case)]
+    fn Initialize(&self, pszfilepath: &PCWSTR, _grfmode: u32) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            // Guard against repeated initialization calls, shared with IInitializeWithStream
+            let mut source_guard = self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?;
+            if source_guard.is_some() {
+                log_warn!("InitializeWithFile: Warning - Already initialized");
+                return Err(Error::from(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)));
+            }
+
+            if pszfilepath.is_null() {
+                log_error!("InitializeWithFile: Error - File path was null.");
+                return Err(E_INVALIDARG.into());
+            }
+
+            let file_path = match unsafe { pszfilepath.to_string() } {
+                Ok(path) => PathBuf::from(path),
+                Err(_) => {
+                    log_error!("InitializeWithFile: Error - File path was not valid UTF-16.");
+                    return Err(E_INVALIDARG.into());
+                }
+            };
+            log_debug!("InitializeWithFile: Deferring read of {}", file_path.display());
+
+            // Only remember the path here, the file is read when the thumbnail is actually requested
+            *source_guard = Some(ThumbnailSource::File(file_path));
+            Ok(())
+        })
+    }
+}
+
+/// Reads a file into memory, respecting the same size limit as the stream path
+fn read_file_data(file_path: &std::path::Path) -> Result<StreamData> {
+    let io_error = |e: std::io::Error| match e.raw_os_error() {
+        Some(code) => Error::from(HRESULT::from_win32(code as u32)),
+        None => Error::new(E_FAIL, "Failed to read file"),
+    };
+
+    let file = std::fs::File::open(file_path).map_err(io_error)?;
+
+    // Fast Fail Check: Ask the file system for the size for a quick rejection.
+    let file_size = file.metadata().map_err(io_error)?.len();
+    reject_if_too_large(file_size).inspect_err(|_| log_debug!("read_file_data: Rejected {} by its size", file_path.display()))?;
+
+    // Extra safety net in case the file grew after the size check, never read more than one byte over the cap
+    let mut buffer: Vec<u8> = Vec::with_capacity(file_size as usize);
+    file.take(config().max_file_size + 1).read_to_end(&mut buffer).map_err(io_error)?;
+    reject_if_too_large(buffer.len() as u64)?;
+
+    // The renderer only needs the file name, e.g. to detect the format from the extension
+    let stream_name = file_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+
+    Ok(StreamData { stream_bytes: buffer.into_boxed_slice(), stream_name, source_path: Some(file_path.to_path_buf()) })
+}
+
+impl Shell::IInitializeWithItem_Impl for ThumbnailProvider_Impl {
+    // Used for items that aren't plain files, e.g. cloud placeholders or virtual folders.
+    // The item is opened as a stream and read like in IInitializeWithStream, with the same size cap.
+    #[allow(non_snake_case)]
+    fn Initialize(&self, psi: Ref<'_, Shell::IShellItem>, _grfmode: u32) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            // Guard against repeated initialization calls, shared with the other Initialize methods
+            if self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?.is_some() {
+                log_warn!("Initialize (item): Warning - Already initialized");
+                return Err(Error::from(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)));
+            }
+
+            let Some(item) = &*psi else {
+                log_error!("Initialize (item): Error - Shell item was null.");
+                return Err(E_INVALIDARG.into());
+            };
+
+            let stream: Com::IStream = unsafe { item.BindToHandler(None, &Shell::BHID_Stream) }.inspect_err(|e| {
+                log_error!("Initialize (item): Error - Could not open the item as a stream: {:?}", e);
+            })?;
+            let mut stream_data = read_stream_data(&stream)?;
+
+            // Not every item stream reports a name, but the renderer needs the extension to detect the sample format
+            if stream_data.stream_name.is_empty() {
+                if let Ok(item_name) = unsafe { item.GetDisplayName(Shell::SIGDN_PARENTRELATIVEPARSING) } {
+                    let item_name_guard = CoTaskMemFreeGuard(item_name);
+                    stream_data.stream_name = unsafe { item_name_guard.0.to_string() }.unwrap_or_default();
+                }
+            }
+            // Items that aren't plain files (e.g. in a virtual folder) have no file system path, and never get the badge
+            if let Ok(item_path) = unsafe { item.GetDisplayName(Shell::SIGDN_FILESYSPATH) } {
+                let item_path_guard = CoTaskMemFreeGuard(item_path);
+                stream_data.source_path = unsafe { item_path_guard.0.to_string() }.ok().map(PathBuf::from);
+            }
+
+            *self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))? = Some(ThumbnailSource::Stream(Arc::new(stream_data)));
+            Ok(())
+        })
+    }
+}
+
+impl Shell::IThumbnailSettings_Impl for ThumbnailProvider_Impl {
+    // The shell calls this before GetThumbnail. WTSCF_FAST means it would rather have no thumbnail than wait for one,
+    // e.g. while scrolling quickly. Other context flags (square, wide, tall) don't change how we render.
+    #[allow(non_snake_case)]
+    fn SetContext(&self, dwcontext: Shell::WTS_CONTEXTFLAGS) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let fast = (dwcontext.0 & Shell::WTSCF_FAST.0) != 0;
+            log_trace!("SetContext: Context flags {:#x}, fast: {}", dwcontext.0, fast);
+            self.fast_context.store(fast, Ordering::Relaxed);
+            Ok(())
+        })
+    }
+}
+
+impl Shell::IThumbnailProvider_Impl for ThumbnailProvider_Impl {
+    #[allow(non_snake_case)]
+    fn GetThumbnail(&self, cx: u32, phbmp: *mut Gdi::HBITMAP, pdwalpha: *mut Shell::WTS_ALPHATYPE) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            // log_debug!("GetThumbnail: Entered with size: {}x{}", cx, cx);
+
+            // Initialize output parameters to safe defaults (COM contract requirement)
+            // pdwalpha is set to UNKNOWN for all failure cases, only changed to ARGB on success
+            unsafe {
+                *phbmp = Gdi::HBITMAP(std::ptr::null_mut());
+                *pdwalpha = Shell::WTSAT_UNKNOWN;
+            }
+
+            // Clone the Arc (cheap pointer copy) or the path and release the mutex before reading or rendering to prevent deadlocks
+            let source = {
+                let source_guard = self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?;
+
+                match source_guard.as_ref() {
+                    Some(ThumbnailSource::Stream(data)) => {
+                        // log_debug!("GetThumbnail: SDR data is {} bytes.", data.len());
+                        ThumbnailSource::Stream(Arc::clone(data)) // Clone the Arc (cheap pointer copy)
+                    }
+                    Some(ThumbnailSource::File(file_path)) => ThumbnailSource::File(file_path.clone()),
+                    None => {
+                        log_error!("GetThumbnail: Error - SDR data was not initialized.");
+                        return Err(Error::new(E_UNEXPECTED, "SDR data not initialized"));
+                    }
+                }
+            }; // Mutex lock is released here
+
+            let stream_data = match source {
+                ThumbnailSource::Stream(data) => data,
+                ThumbnailSource::File(file_path) => {
+                    // Lazily read the file on first use, then keep the data like the stream path does
+                    let data = Arc::new(read_file_data(&file_path)?);
+                    *self.source.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))? = Some(ThumbnailSource::Stream(Arc::clone(&data)));
+                    data
+                }
+            };
+
+            let config = config();
+            let render_size = if config.enable_cache_size_rounding {
+                round_up_to_cache_size(cx)
+            } else {
+                cx
+            };
+            if render_size != cx {
+                log_debug!("GetThumbnail: Rounded requested size {} up to cache size {}", cx, render_size);
+            }
+
+            // Honoring the fast hint needs the disk cache, without it every request would fail
+            let cache_only = self.fast_context.load(Ordering::Relaxed)
+                && config.enable_fast_context_cache_only
+                && config.enable_thumbnail_cache;
+
+            match render_thumbnail_with_timeout(stream_data, render_size, cache_only) {
+                Ok(hbitmap) => {
+                    // log_debug!("GetThumbnail: render_sdr_to_hbitmap succeeded.");
+                    unsafe {
+                        *phbmp = hbitmap;
+                        *pdwalpha = Shell::WTSAT_ARGB;
+                    }
+                    // log_debug!("GetThumbnail: Succeeded.");
+                    Ok(())
+                }
+                Err(e) if e.code() == E_PENDING => {
+                    // Not a broken file (no cached thumbnail for a fast request, or a timed out render still running),
+                    // so don't hand out the fallback image which the shell would cache
+                    log_debug!("GetThumbnail: No thumbnail available right now, failing fast: {}", e.message());
+                    Err(e)
+                }
+                Err(e) => {
+                    log_warn!("GetThumbnail: render_sdr_to_hbitmap failed with error: {:?}", e);
+
+                    // Instead of returning an error, create a fallback thumbnail at the same size a successful render would have
+                    match create_fallback_thumbnail(render_size) {
+                        Ok(fallback_hbitmap) => {
+                            log_info!("GetThumbnail: Created fallback thumbnail for invalid SDR.");
+                            unsafe {
+                                *phbmp = fallback_hbitmap;
+                                *pdwalpha = Shell::WTSAT_ARGB;
+                            }
+                            Ok(())
+                        }
+                        Err(fallback_err) => {
+                            log_error!("GetThumbnail: Failed to create fallback thumbnail: {:?}", fallback_err);
+                            Err(e) // Only return error if we can't even create a fallback
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Renders a thumbnail on a worker thread and waits at most the configured render timeout for it, so a pathological file
+/// can't stall Explorer's thumbnail thread. The renderer can't be cancelled mid-flight: on timeout the render keeps
+/// running in the background and its bitmap is deleted once it finishes. The timeout bounds how long the shell waits,
+/// not how long the CPU stays busy. While a render left behind like this is still running, new requests fail with
+/// E_PENDING instead of piling up more runaway renders. Renders already in flight on other threads when it timed out
+/// still finish or time out on their own, so at most one runaway render per shell thread can accumulate.
+fn render_thumbnail_with_timeout(stream_data: Arc<StreamData>, size: u32, cache_only: bool) -> Result<Gdi::HBITMAP> {
+    let timeout_ms = config().render_timeout_ms;
+    if timeout_ms == 0 {
+        let request = RenderRequest { source_path: stream_data.source_path.as_deref(), width: size, height: size, cache_only };
+        return create_thumbnail_hbitmap(&stream_data.stream_bytes, &stream_data.stream_name, &request);
+    }
+
+    // The lock is only held for this check and to store a timed out worker, so renders still run in parallel
+    if !reap_abandoned_renders(&mut ABANDONED_RENDERS.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?) {
+        log_warn!("render_thumbnail_with_timeout: Warning - A timed out render is still running, refusing to start another");
+        return Err(RenderError::RenderBusy.into());
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel::<Result<HBitmapGuard>>();
+    let worker_data = Arc::clone(&stream_data);
+    let spawn_result = std::thread::Builder::new().name("win_sdr_thumbs render".into()).spawn(move || {
+        // The optional badge image is decoded with WIC, which needs COM on this thread too
+        let com_initialized = unsafe { Com::CoInitializeEx(None, Com::COINIT_MULTITHREADED) }.is_ok();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let request = RenderRequest { source_path: worker_data.source_path.as_deref(), width: size, height: size, cache_only };
+            create_thumbnail_hbitmap(&worker_data.stream_bytes, &worker_data.stream_name, &request).map(HBitmapGuard::new)
+        })).unwrap_or_else(|_| Err(E_FAIL.into()));
+        // If GetThumbnail stopped waiting the send fails, and dropping the returned guard deletes the bitmap
+        let _ = sender.send(result);
+        if com_initialized {
+            unsafe { Com::CoUninitialize() };
+        }
+    });
+
+    let worker = match spawn_result {
+        Ok(worker) => worker,
+        Err(e) => {
+            // Rendering inline instead would give up the deadline the timeout is there to guarantee
+            log_error!("render_thumbnail_with_timeout: Could not start render thread: {}", e);
+            return Err(RenderError::RenderBusy.into());
+        }
+    };
+
+    match receiver.recv_timeout(std::time::Duration::from_millis(timeout_ms as u64)) {
+        Ok(result) => {
+            let _ = worker.join();
+            result.map(HBitmapGuard::release)
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            log_error!("render_thumbnail_with_timeout: Render of {} ({} bytes) exceeded the timeout of {} ms", stream_data.stream_name, stream_data.stream_bytes.len(), timeout_ms);
+            // Kept so DllCanUnloadNow refuses to unload while the worker still runs code from this DLL
+            ABANDONED_RENDERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(worker);
+            Err(RenderError::TimedOut { timeout_ms }.into())
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            let _ = worker.join();
+            Err(Error::new(E_FAIL, "Render thread exited without a result"))
+        }
+    }
+}
+
+/// Joins the renders left behind by a timeout that have finished since. Returns false if any is still running.
+/// Joining waits for the thread to fully exit, so none of its teardown runs DLL code after this returns true.
+fn reap_abandoned_renders(abandoned_renders: &mut Vec<std::thread::JoinHandle<()>>) -> bool {
+    let (finished, running): (Vec<_>, Vec<_>) = abandoned_renders.drain(..).partition(|worker| worker.is_finished());
+    for worker in finished {
+        let _ = worker.join();
+        log_debug!("reap_abandoned_renders: Timed out render has finished");
+    }
+    *abandoned_renders = running;
+    abandoned_renders.is_empty()
+}
+
+/// Rounds a requested thumbnail size up to the next standard Explorer cache size, see STANDARD_THUMBNAIL_SIZES.
+/// Sizes above the largest standard size, or where the rounded size would exceed the maximum dimension, are kept as is.
+fn round_up_to_cache_size(size: u32) -> u32 {
+    STANDARD_THUMBNAIL_SIZES
+        .iter()
+        .copied()
+        .find(|&standard_size| standard_size >= size)
+        .filter(|&standard_size| standard_size <= config().max_dimension)
+        .unwrap_or(size)
+}
+
+/// Creates a simple fallback thumbnail for invalid SDR files
+fn create_fallback_thumbnail(size: u32) -> Result<Gdi::HBITMAP> {
+    // log_debug!("create_fallback_thumbnail: Creating fallback thumbnail of size {}x{}", size, size);
+
+    // A user-supplied fallback image takes precedence, any problem with it drops through to the built-in fallback
+    if let Some(image_path) = &config().fallback_image_path {
+        match render_image_file_to_hbitmap(image_path, size) {
+            Ok(hbitmap) => {
+                log_debug!("create_fallback_thumbnail: Successfully created fallback from '{}'", image_path.display());
+                return Ok(hbitmap);
+            }
+            Err(e) => log_warn!("create_fallback_thumbnail: Custom fallback image '{}' failed: {:?}, using the built-in fallback", image_path.display(), e),
+        }
+    }
+
+    // The built-in "broken file" thumbnail is a red X on a transparent background, drawn directly into the bitmap
+    let bmi = Gdi::BITMAPINFO {
+        bmiHeader: Gdi::BITMAPINFOHEADER {
+            biSize: std::mem::size_of::<Gdi::BITMAPINFOHEADER>() as u32,
+            biWidth: size as i32,
+            biHeight: -(size as i32), // Negative for top-down DIB
+            biPlanes: 1,
+            biBitCount: 32,
+            biCompression: Gdi::BI_RGB.0 as u32,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut dib_data: *mut std::ffi::c_void = std::ptr::null_mut();
+    let hbitmap_handle: Gdi::HBITMAP = unsafe {
+        Gdi::CreateDIBSection(None, &bmi, Gdi::DIB_RGB_COLORS, &mut dib_data, None, 0)
+    }?;
+    let hbitmap_guard = HBitmapGuard::new(hbitmap_handle);
+    if dib_data.is_null() {
+        return Err(RenderError::BitmapCreation(None).into());
+    }
+
+    // A fresh DIB section is zeroed, which is fully transparent
+    let pixels: &mut [u32] = unsafe { std::slice::from_raw_parts_mut(dib_data as *mut u32, size as usize * size as usize) };
+    draw_fallback_cross(pixels, size);
+
+    log_debug!("create_fallback_thumbnail: Successfully created built-in fallback");
+    Ok(hbitmap_guard.release())
+}
+
+/// Draws the two diagonals of a square BGRA buffer in opaque red. The line width scales with the size,
+/// 2 pixels at 256x256, but is at least one pixel so small thumbnails still show the cross.
+fn draw_fallback_cross(pixels: &mut [u32], size: u32) {
+    const OPAQUE_RED: u32 = 0xFFFF0000;
+    let line_width = (size / 128).max(1) as i64;
+    let last = size as i64 - 1;
+
+    for (y, row) in pixels.chunks_exact_mut(size as usize).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let (x, y) = (x as i64, y as i64);
+            if (x - y).abs() < line_width || (x + y - last).abs() < line_width {
+                *pixel = OPAQUE_RED;
+            }
+        }
+    }
+}
+
+/// Decodes an image file (anything WIC can read, e.g. PNG, BMP or ICO) and scales it into a square 32bpp DIB section.
+/// The file goes through read_file_data, so the same size cap as for SDR files applies. Requires COM to be initialized.
+fn render_image_file_to_hbitmap(image_path: &Path, size: u32) -> Result<Gdi::HBITMAP> {
+    validate_dimensions(size, size)?;
+
+    let bmi = Gdi::BITMAPINFO { bmiHeader: Gdi::BITMAPINFOHEADER {
+        biSize: std::mem::size_of::<Gdi::BITMAPINFOHEADER>() as u32, biWidth: size as i32, biHeight: -(size as i32),
+        biPlanes: 1, biBitCount: 32, biCompression: Gdi::BI_RGB.0 as u32, ..Default::default()
+    }, ..Default::default() };
+
+    let mut dib_data: *mut std::ffi::c_void = std::ptr::null_mut();
+    let hbitmap_guard = HBitmapGuard::new(unsafe { Gdi::CreateDIBSection(None, &bmi, Gdi::DIB_RGB_COLORS, &mut dib_data, None, 0) }?);
+    if dib_data.is_null() {
+        return Err(RenderError::BitmapCreation(None).into());
+    }
+
+    let pixels = unsafe { std::slice::from_raw_parts_mut(dib_data as *mut u32, size as usize * size as usize) };
+    decode_image_file(image_path, size, size, pixels)?;
+    Ok(hbitmap_guard.release())
+}
+
+/// Decodes an image file via WIC and scales it to fill `pixels` as straight-alpha BGRA of the given size.
+/// The file goes through read_file_data, so the same size cap as for SDR files applies. Requires COM to be initialized.
+fn decode_image_file(image_path: &Path, width: u32, height: u32, pixels: &mut [u32]) -> Result<()> {
+    let image_data = read_file_data(image_path)?;
+
+    unsafe {
+        let factory: Graphics::Imaging::IWICImagingFactory = Com::CoCreateInstance(&Graphics::Imaging::CLSID_WICImagingFactory, None, Com::CLSCTX_INPROC_SERVER)?;
+        let stream = factory.CreateStream()?;
+        stream.InitializeFromMemory(&image_data.stream_bytes)?;
+
+        let decoder = factory.CreateDecoderFromStream(&stream, std::ptr::null(), Graphics::Imaging::WICDecodeMetadataCacheOnDemand)?;
+        let frame = decoder.GetFrame(0)?;
+
+        let scaler = factory.CreateBitmapScaler()?;
+        scaler.Initialize(&frame, width, height, Graphics::Imaging::WICBitmapInterpolationModeFant)?;
+
+        // 32bpp BGRA is straight alpha, which is what the shell expects for WTSAT_ARGB
+        let converter = factory.CreateFormatConverter()?;
+        converter.Initialize(
+            &scaler,
+            &Graphics::Imaging::GUID_WICPixelFormat32bppBGRA,
+            Graphics::Imaging::WICBitmapDitherTypeNone,
+            None,
+            0.0,
+            Graphics::Imaging::WICBitmapPaletteTypeCustom,
+        )?;
+
+        let buffer = std::slice::from_raw_parts_mut(pixels.as_mut_ptr() as *mut u8, pixels.len() * 4);
+        converter.CopyPixels(std::ptr::null(), width * 4, buffer)?;
+        Ok(())
+    }
+}
+
+/// Whether `path` is inside `folder` or one of its subfolders. Compared per path component and case-insensitively,
+/// like the file system does.
+fn is_in_folder(path: &Path, folder: &Path) -> bool {
+    let mut path_components = path.components();
+    folder.components().all(|folder_component| {
+        path_components.next().is_some_and(|path_component| {
+            path_component.as_os_str().to_string_lossy().to_lowercase() == folder_component.as_os_str().to_string_lossy().to_lowercase()
+        })
+    })
+}
+
+// Number of decoded badges kept, one per thumbnail size in use is plenty
+const MAX_BADGE_CACHE_ENTRIES: usize = 16;
+
+// A badge image decoded at one size, or None if it couldn't be loaded so we don't retry on every thumbnail
+struct BadgeCacheEntry {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    pixels: Option<Arc<[u32]>>,
+}
+
+/// Returns the badge image decoded at the given size, decoding it only the first time each size is asked for.
+/// Cleared by reload_config, so a changed badge file is picked up along with the other settings.
+fn badge_pixels(badge_path: &Path, width: u32, height: u32) -> Option<Arc<[u32]>> {
+    let find_cached = |cache: &Vec<BadgeCacheEntry>| {
+        cache.iter().find(|entry| entry.width == width && entry.height == height && entry.path == badge_path).map(|entry| entry.pixels.clone())
+    };
+    if let Some(pixels) = find_cached(&*BADGE_CACHE.lock().ok()?) {
+        return pixels;
+    }
+
+    // Decoded without holding the lock, two threads decoding the same size at once just do it twice
+    let mut decoded = vec![0u32; width as usize * height as usize];
+    let pixels = match decode_image_file(badge_path, width, height, &mut decoded) {
+        Ok(()) => Some(Arc::from(decoded)),
+        Err(e) => {
+            log_warn!("badge_pixels: Could not load badge image '{}': {:?}", badge_path.display(), e);
+            None
+        }
+    };
+
+    let mut cache = BADGE_CACHE.lock().ok()?;
+    if find_cached(&*cache).is_none() {
+        if cache.len() >= MAX_BADGE_CACHE_ENTRIES {
+            cache.remove(0);
+        }
+        cache.push(BadgeCacheEntry { path: badge_path.to_path_buf(), width, height, pixels: pixels.clone() });
+    }
+    pixels
+}
+
+/// Draws the configured badge image into the bottom right quadrant of a rendered thumbnail, e.g. to mark files
+/// from a work-in-progress folder. Composited with straight alpha, so a transparent badge keeps the thumbnail
+/// visible underneath. A badge that can't be loaded is logged and skipped, it never fails the render.
+fn apply_badge_overlay(badge_path: &Path, pixels: &mut [u32], width: u32, height: u32) {
+    let (badge_width, badge_height) = (width / 2, height / 2);
+    if badge_width == 0 || badge_height == 0 {
+        return;
+    }
+
+    let Some(badge_pixels) = badge_pixels(badge_path, badge_width, badge_height) else {
+        return;
+    };
 
-    // 8. Copy pixels from the mapped D2D buffer to the GDI HBITMAP buffer
-    if !dib_data.is_null() {
-        let file_name = std::ffi::CString::new(sdr_name).unwrap();
-        // Safety: The bitmap bit values are aligned on doubleword boundaries
-        let pixels = dib_data as *mut u32;
-        unsafe {
-            let _ret = splt_thumbnail(sdr_data.as_ptr(), sdr_data.len() as u64, file_name.as_ptr(), requested_width, requested_height, pixels);
-            // should use a BGR palette, reorder RGBA for now
-            let p_u32 = std::slice::from_raw_parts_mut(pixels, requested_width as usize * requested_height as usize);
-            for x in p_u32 {
-                let b = (*x).to_le_bytes();
-                *x = u32::from_le_bytes([b[2], b[1], b[0], b[3]]);
-            }
+    let (offset_x, offset_y) = ((width - badge_width) as usize, (height - badge_height) as usize);
+    for (badge_y, badge_row) in badge_pixels.chunks_exact(badge_width as usize).enumerate() {
+        let row_start = (offset_y + badge_y) * width as usize + offset_x;
+        for (pixel, &badge_pixel) in pixels[row_start..row_start + badge_width as usize].iter_mut().zip(badge_row) {
+            *pixel = blend_straight_alpha(badge_pixel, *pixel);
         }
     }
+}
 
-    log_message("render_sdr_to_hbitmap: Successfully completed rendering");
-    Ok(hbitmap_guard.release())
+/// Composites a straight-alpha BGRA pixel over another one ("source over")
+fn blend_straight_alpha(source: u32, destination: u32) -> u32 {
+    let [source_b, source_g, source_r, source_a] = source.to_le_bytes();
+    let [destination_b, destination_g, destination_r, destination_a] = destination.to_le_bytes();
+    if source_a == 255 || destination_a == 0 {
+        return source;
+    }
+    if source_a == 0 {
+        return destination;
+    }
+
+    let source_alpha = source_a as u32;
+    let destination_weight = destination_a as u32 * (255 - source_alpha) / 255;
+    let out_alpha = source_alpha + destination_weight;
+    let blend = |source_channel: u8, destination_channel: u8| {
+        ((source_channel as u32 * source_alpha + destination_channel as u32 * destination_weight) / out_alpha) as u8
+    };
+    u32::from_le_bytes([blend(source_b, destination_b), blend(source_g, destination_g), blend(source_r, destination_r), out_alpha as u8])
 }
 
 // =================================================================
-//                 COM Thumbnail Provider Object
+//                 COM Preview Handler Object
 // =================================================================
 
-struct StreamData {
-    stream_bytes: Box<[u8]>,
-    stream_name: String,
+// Window class of the child window the preview is drawn into
+const PREVIEW_WINDOW_CLASS: PCWSTR = w!("win_sdr_thumbs_preview");
+// Window property holding the HBITMAP the child window paints
+const PREVIEW_BITMAP_PROP: PCWSTR = w!("win_sdr_thumbs_preview_bitmap");
+// Whether this DLL load registered the preview window class, and the lock serializing the registration
+static PREVIEW_CLASS_REGISTERED: AtomicBool = AtomicBool::new(false);
+static PREVIEW_CLASS_LOCK: Mutex<()> = Mutex::new(());
+
+struct PreviewState {
+    stream_data: Option<Arc<StreamData>>,
+    parent_hwnd: HWND,
+    rect: RECT,
+    preview_hwnd: Option<HWND>,
+    // Owned by the state, the child window only borrows it through PREVIEW_BITMAP_PROP
+    preview_bitmap: Option<HBitmapGuard>,
+    background_color: COLORREF,
+    site: Option<IUnknown>,
 }
 
-#[implement(Shell::PropertiesSystem::IInitializeWithStream, Shell::IThumbnailProvider)]
-struct ThumbnailProvider {
-    stream_data: Mutex<Option<Arc<StreamData>>>,
+#[implement(
+    Shell::PropertiesSystem::IInitializeWithStream,
+    Shell::IPreviewHandler,
+    Shell::IPreviewHandlerVisuals,
+    System::Ole::IOleWindow,
+    System::Ole::IObjectWithSite
+)]
+struct PreviewHandler {
+    state: Mutex<PreviewState>,
 }
 
-impl Default for ThumbnailProvider {
+impl Default for PreviewHandler {
     fn default() -> Self {
         dll_add_ref();
-        log_message("ThumbnailProvider: Created new instance");
+        log_trace!("PreviewHandler: Created new instance");
         Self {
-            stream_data: Mutex::new(None),
+            state: Mutex::new(PreviewState {
+                stream_data: None,
+                parent_hwnd: HWND::default(),
+                rect: RECT::default(),
+                preview_hwnd: None,
+                preview_bitmap: None,
+                background_color: COLORREF(unsafe { Gdi::GetSysColor(Gdi::COLOR_WINDOW) }),
+                site: None,
+            }),
         }
     }
 }
 
-impl Drop for ThumbnailProvider {
+impl Drop for PreviewHandler {
     fn drop(&mut self) {
-        log_message("ThumbnailProvider: Dropping instance");
+        log_trace!("PreviewHandler: Dropping instance");
+        if let Ok(state) = self.state.get_mut() {
+            destroy_preview_window(state);
+        }
         dll_release();
     }
 }
 
-impl Shell::PropertiesSystem::IInitializeWithStream_Impl for ThumbnailProvider_Impl {
-    #[allow(non_snake_case)]
-    fn Initialize(&self, pstream: Ref<'_, Com::IStream>, _grfmode: u32) -> Result<()> {
-        ffi_guard!(Result<()>, {
-            // log_message("Initialize: Starting SDR data loading");
+impl PreviewHandler_Impl {
+    fn lock_state(&self) -> Result<std::sync::MutexGuard<'_, PreviewState>> {
+        self.state.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))
+    }
+}
 
-            // Guard against repeated initialization calls
-            if self.stream_data.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?.is_some() {
-                log_message("Initialize: Error - Already initialized");
-                return Err(Error::from(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)));
-            }
+/// Renders the SDR data at the size of the preview rectangle and hands the bitmap to the child window.
+fn update_preview_bitmap(state: &mut PreviewState) -> Result<()> {
+    let (preview_hwnd, stream_data) = match (state.preview_hwnd, state.stream_data.as_ref()) {
+        (Some(hwnd), Some(data)) => (hwnd, Arc::clone(data)),
+        _ => return Ok(()),
+    };
 
-            match &*pstream {
-                Some(stream) => {
-                    // 101 MiB max file size.
-                    const MAX_SIZE: u64 = 101 * 1024 * 1024;
-                    pub const ERROR_FILE_TOO_LARGE: WIN32_ERROR = WIN32_ERROR(223u32);
-
-                    // Fast Fail Check: Ask the stream for its size for a quick rejection.
-                    // If the size check fails continue to read the stream in chunks, there is another safety net below.
-                    let mut statstg = Default::default();
-                    // FIXME: also stat the name to detect the file extension
-                    let mut stream_name = String::default();
-                    if unsafe { stream.Stat(&mut statstg, Com::STATFLAG_DEFAULT) }.is_ok() {
-                        let stream_name_guard = CoTaskMemFreeGuard(statstg.pwcsName);
-                        if let Ok(s) = unsafe { stream_name_guard.0.to_string() } {
-                            stream_name = s;
-                        }
+    let width = (state.rect.right - state.rect.left).max(0) as u32;
+    let height = (state.rect.bottom - state.rect.top).max(0) as u32;
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
 
-                        let stream_size = statstg.cbSize;
-                        // log_message(&format!("Initialize: Stream reports size: {} bytes", stream_size));
-                        if stream_size > 0 && stream_size > MAX_SIZE {
-                            log_message(&format!("Initialize: Error - File too large: {} bytes (max: {} bytes) in {}", stream_size, MAX_SIZE, stream_name));
-                            return Err(Error::from(HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0)));
-                        }
-                    } else {
-                        log_message("Initialize: Warning - Could not get stream size, will read with safety checks");
-                    }
+    let hbitmap_guard = HBitmapGuard::new(render_sdr_to_hbitmap(&stream_data.stream_bytes, &stream_data.stream_name, width, height)?);
 
-                    // Do not trust the reported size for allocation.
-                    // Start with a default-sized Vec and let it grow.
-                    let seq_stream: Com::ISequentialStream = stream.cast()?;
-                    let mut buffer: Vec<u8> = Vec::new();
-                    let mut chunk: Vec<u8> = vec![0u8; 65536];
-
-                    loop {
-                        let mut bytes_read: u32 = 0;
-                        let hr: HRESULT = unsafe {
-                            seq_stream.Read(
-                                chunk.as_mut_ptr() as *mut core::ffi::c_void,
-                                chunk.len() as u32,
-                                Some(&mut bytes_read)
-                            )
-                        };
-
-                        if hr.is_err() || bytes_read == 0 {
-                            if hr.is_err() {
-                                log_message(&format!("Initialize: Stream read error: {:?}", hr));
-                            }
-                            break;
-                        }
+    // The pane has no alpha channel, so blend transparent areas onto the background color the host asked for
+    let mut dib_section = Gdi::DIBSECTION::default();
+    let object_size = unsafe {
+        Gdi::GetObjectW(Gdi::HGDIOBJ(hbitmap_guard.0.0), std::mem::size_of::<Gdi::DIBSECTION>() as i32, Some(&mut dib_section as *mut _ as *mut std::ffi::c_void))
+    };
+    if object_size as usize == std::mem::size_of::<Gdi::DIBSECTION>() && !dib_section.dsBm.bmBits.is_null() {
+        let pixels = unsafe { std::slice::from_raw_parts_mut(dib_section.dsBm.bmBits as *mut u32, width as usize * height as usize) };
+        let [r, g, b, _] = state.background_color.0.to_le_bytes();
+        fill_transparent_background(pixels, b as u32, g as u32, r as u32);
+    }
 
-                        // Extra file size safety net protects memory usage in case statstg failed or returned a wrong size.
-                        if buffer.len() + (bytes_read as usize) > (MAX_SIZE as usize) {
-                            log_message(&format!("Initialize: Error - File too large during read: {} bytes (max: {} bytes)", buffer.len() + (bytes_read as usize), MAX_SIZE));
-                            return Err(Error::from(HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0)));
-                        }
+    // Point the window at the new bitmap before the old one is dropped
+    unsafe {
+        SetPropW(preview_hwnd, PREVIEW_BITMAP_PROP, Some(HANDLE(hbitmap_guard.0.0)))?;
+        let _ = Gdi::InvalidateRect(Some(preview_hwnd), None, true);
+    }
+    state.preview_bitmap = Some(hbitmap_guard);
+    Ok(())
+}
 
-                        buffer.extend_from_slice(&chunk[..bytes_read as usize]);
-                    }
+fn destroy_preview_window(state: &mut PreviewState) {
+    if let Some(preview_hwnd) = state.preview_hwnd.take() {
+        unsafe {
+            let _ = RemovePropW(preview_hwnd, PREVIEW_BITMAP_PROP);
+            let _ = DestroyWindow(preview_hwnd);
+        }
+    }
+    state.preview_bitmap = None;
+}
+
+/// Registers the preview window class once per DLL load. Windows doesn't unregister a DLL's classes when it is
+/// unloaded, so DllMain unregisters it again, see unregister_preview_window_class.
+fn register_preview_window_class() -> Result<()> {
+    let _registration_guard = PREVIEW_CLASS_LOCK.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?;
+    if PREVIEW_CLASS_REGISTERED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    let hinstance = HINSTANCE(MODULE_HANDLE.load(Ordering::Acquire));
+    let window_class = WNDCLASSW {
+        hInstance: hinstance,
+        lpszClassName: PREVIEW_WINDOW_CLASS,
+        lpfnWndProc: Some(preview_window_proc),
+        hCursor: unsafe { LoadCursorW(None, IDC_ARROW) }.unwrap_or_default(),
+        ..Default::default()
+    };
+    let mut atom = unsafe { RegisterClassW(&window_class) };
+    if atom == 0 && unsafe { GetLastError() } == ERROR_CLASS_ALREADY_EXISTS {
+        // Left behind by an earlier load of this DLL that wasn't unloaded cleanly, its window procedure may point at
+        // code that is gone. No window of it can exist any more, so replace it.
+        log_warn!("register_preview_window_class: Warning - Replacing a stale preview window class");
+        let _ = unsafe { UnregisterClassW(PREVIEW_WINDOW_CLASS, Some(hinstance)) };
+        atom = unsafe { RegisterClassW(&window_class) };
+    }
+
+    if atom == 0 {
+        return Err(Error::new(E_FAIL, "Failed to register preview window class"));
+    }
+    PREVIEW_CLASS_REGISTERED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Unregisters the preview window class if this DLL load registered it, so the next load can register it again
+fn unregister_preview_window_class() {
+    if PREVIEW_CLASS_REGISTERED.swap(false, Ordering::AcqRel) {
+        let hinstance = HINSTANCE(MODULE_HANDLE.load(Ordering::Acquire));
+        let _ = unsafe { UnregisterClassW(PREVIEW_WINDOW_CLASS, Some(hinstance)) };
+    }
+}
+
+extern "system" fn preview_window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let result = catch_unwind(AssertUnwindSafe(|| unsafe {
+        match msg {
+            WM_PAINT => {
+                let mut paint_struct = Gdi::PAINTSTRUCT::default();
+                let hdc = Gdi::BeginPaint(hwnd, &mut paint_struct);
+
+                let bitmap_handle = GetPropW(hwnd, PREVIEW_BITMAP_PROP);
+                if !bitmap_handle.is_invalid() {
+                    let mut bitmap = Gdi::BITMAP::default();
+                    Gdi::GetObjectW(Gdi::HGDIOBJ(bitmap_handle.0), std::mem::size_of::<Gdi::BITMAP>() as i32, Some(&mut bitmap as *mut _ as *mut std::ffi::c_void));
+
+                    let memory_dc = Gdi::CreateCompatibleDC(Some(hdc));
+                    let previous_object = Gdi::SelectObject(memory_dc, Gdi::HGDIOBJ(bitmap_handle.0));
+                    let _ = Gdi::BitBlt(hdc, 0, 0, bitmap.bmWidth, bitmap.bmHeight, Some(memory_dc), 0, 0, Gdi::SRCCOPY);
+                    Gdi::SelectObject(memory_dc, previous_object);
+                    let _ = Gdi::DeleteDC(memory_dc);
+                }
 
-                    // log_message(&format!("Initialize: Successfully loaded {} bytes of SDR data", buffer.len()));
+                let _ = Gdi::EndPaint(hwnd, &paint_struct);
+                LRESULT(0)
+            }
+            // The bitmap covers the whole window, skip erasing to avoid flicker
+            WM_ERASEBKGND => LRESULT(1),
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }));
+    result.unwrap_or(LRESULT(0))
+}
 
-                    // Convert to Arc<[u8]> to save memory overhead
-                    let stream_bytes = buffer.into_boxed_slice();
-                    *self.stream_data.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))? = Some(Arc::new(StreamData { stream_bytes, stream_name }));
+impl Shell::PropertiesSystem::IInitializeWithStream_Impl for PreviewHandler_Impl {
+    #[allow(non_snake_case)]
+    fn Initialize(&self, pstream: Ref<'_, Com::IStream>, _grfmode: u32) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let mut state = self.lock_state()?;
+            if state.stream_data.is_some() {
+                log_warn!("PreviewHandler::Initialize: Warning - Already initialized");
+                return Err(Error::from(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)));
+            }
 
-                    // log_message("Initialize: Succeeded.");
+            match &*pstream {
+                Some(stream) => {
+                    state.stream_data = Some(Arc::new(read_stream_data(stream)?));
                     Ok(())
                 }
                 None => {
-                    // This case handles if Windows passes a null stream.
-                    log_message("Initialize: Error - Stream was null.");
+                    log_error!("PreviewHandler::Initialize: Error - Stream was null.");
                     Err(E_INVALIDARG.into())
                 }
             }
@@ -306,118 +1692,187 @@ impl Shell::PropertiesSystem::IInitializeWithStream_Impl for ThumbnailProvider_I
     }
 }
 
-impl Shell::IThumbnailProvider_Impl for ThumbnailProvider_Impl {
+impl Shell::IPreviewHandler_Impl for PreviewHandler_Impl {
     #[allow(non_snake_case)]
-    fn GetThumbnail(&self, cx: u32, phbmp: *mut Gdi::HBITMAP, pdwalpha: *mut Shell::WTS_ALPHATYPE) -> Result<()> {
+    fn SetWindow(&self, hwnd: HWND, prc: *const RECT) -> Result<()> {
         ffi_guard!(Result<()>, {
-            // log_message(&format!("GetThumbnail: Entered with size: {}x{}", cx, cx));
-
-            // Initialize output parameters to safe defaults (COM contract requirement)
-            // pdwalpha is set to UNKNOWN for all failure cases, only changed to ARGB on success
-            unsafe {
-                *phbmp = Gdi::HBITMAP(std::ptr::null_mut());
-                *pdwalpha = Shell::WTSAT_UNKNOWN;
+            if prc.is_null() {
+                return Err(E_INVALIDARG.into());
             }
 
-            // Clone the Arc (cheap pointer copy) and release the mutex before rendering to prevent deadlocks
-            let stream_data = {
-                let data_guard = self.stream_data.lock().map_err(|_| Error::new(E_FAIL, "Mutex was poisoned"))?;
+            let mut state = self.lock_state()?;
+            state.parent_hwnd = hwnd;
+            state.rect = unsafe { *prc };
 
-                match data_guard.as_ref() {
-                    Some(data) => {
-                        // log_message(&format!("GetThumbnail: SDR data is {} bytes.", data.len()));
-                        Arc::clone(data) // Clone the Arc (cheap pointer copy)
-                    }
-                    None => {
-                        log_message("GetThumbnail: Error - SDR data was not initialized.");
-                        return Err(Error::new(E_UNEXPECTED, "SDR data not initialized"));
-                    }
+            if let Some(preview_hwnd) = state.preview_hwnd {
+                let rect = state.rect;
+                unsafe {
+                    SetParent(preview_hwnd, Some(hwnd))?;
+                    MoveWindow(preview_hwnd, rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top, true)?;
                 }
-            }; // Mutex lock is released here
+                update_preview_bitmap(&mut state)?;
+            }
+            Ok(())
+        })
+    }
 
-            match render_sdr_to_hbitmap(&stream_data.stream_bytes, &stream_data.stream_name, cx, cx) {
-                Ok(hbitmap) => {
-                    // log_message("GetThumbnail: render_sdr_to_hbitmap succeeded.");
-                    unsafe {
-                        *phbmp = hbitmap;
-                        *pdwalpha = Shell::WTSAT_ARGB;
-                    }
-                    // log_message("GetThumbnail: Succeeded.");
-                    Ok(())
-                }
-                Err(e) => {
-                    log_message(&format!("GetThumbnail: render_sdr_to_hbitmap failed with error: {:?}", e));
+    #[allow(non_snake_case)]
+    fn SetRect(&self, prc: *const RECT) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            if prc.is_null() {
+                return Err(E_INVALIDARG.into());
+            }
 
-                    // Instead of returning an error, create a fallback thumbnail
-                    match create_fallback_thumbnail(cx) {
-                        Ok(fallback_hbitmap) => {
-                            log_message("GetThumbnail: Created fallback thumbnail for invalid SDR.");
-                            unsafe {
-                                *phbmp = fallback_hbitmap;
-                                *pdwalpha = Shell::WTSAT_ARGB;
-                            }
-                            Ok(())
-                        }
-                        Err(fallback_err) => {
-                            log_message(&format!("GetThumbnail: Failed to create fallback thumbnail: {:?}", fallback_err));
-                            Err(e) // Only return error if we can't even create a fallback
-                        }
-                    }
-                }
+            let mut state = self.lock_state()?;
+            state.rect = unsafe { *prc };
+
+            // Re-render at the new pane size rather than stretching the old bitmap
+            if let Some(preview_hwnd) = state.preview_hwnd {
+                let rect = state.rect;
+                unsafe { MoveWindow(preview_hwnd, rect.left, rect.top, rect.right - rect.left, rect.bottom - rect.top, true)? };
+                update_preview_bitmap(&mut state)?;
+            }
+            Ok(())
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn DoPreview(&self) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let mut state = self.lock_state()?;
+            if state.stream_data.is_none() {
+                log_error!("PreviewHandler::DoPreview: Error - SDR data was not initialized.");
+                return Err(Error::new(E_UNEXPECTED, "SDR data not initialized"));
+            }
+
+            if state.preview_hwnd.is_none() {
+                register_preview_window_class()?;
+                let rect = state.rect;
+                let preview_hwnd = unsafe {
+                    CreateWindowExW(
+                        WINDOW_EX_STYLE::default(),
+                        PREVIEW_WINDOW_CLASS,
+                        None,
+                        WS_CHILD | WS_VISIBLE | WS_CLIPSIBLINGS,
+                        rect.left,
+                        rect.top,
+                        rect.right - rect.left,
+                        rect.bottom - rect.top,
+                        Some(state.parent_hwnd),
+                        None,
+                        Some(HINSTANCE(MODULE_HANDLE.load(Ordering::Acquire))),
+                        None,
+                    )
+                }?;
+                state.preview_hwnd = Some(preview_hwnd);
+            }
+
+            log_debug!("PreviewHandler::DoPreview: Rendering preview");
+            update_preview_bitmap(&mut state)
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn Unload(&self) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let mut state = self.lock_state()?;
+            destroy_preview_window(&mut state);
+            state.stream_data = None;
+            Ok(())
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn SetFocus(&self) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let state = self.lock_state()?;
+            if let Some(preview_hwnd) = state.preview_hwnd {
+                unsafe { KeyboardAndMouse::SetFocus(Some(preview_hwnd))? };
+            }
+            Ok(())
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn QueryFocus(&self) -> Result<HWND> {
+        ffi_guard!(Result<HWND>, {
+            let focus_hwnd = unsafe { KeyboardAndMouse::GetFocus() };
+            if focus_hwnd.is_invalid() {
+                Err(Error::from_win32())
+            } else {
+                Ok(focus_hwnd)
             }
         })
     }
+
+    #[allow(non_snake_case)]
+    fn TranslateAccelerator(&self, _pmsg: *const MSG) -> Result<()> {
+        // The preview has no keyboard shortcuts of its own, S_FALSE lets the host handle the message
+        Err(S_FALSE.into())
+    }
 }
 
-/// Creates a simple fallback thumbnail for invalid SDR files
-fn create_fallback_thumbnail(size: u32) -> Result<Gdi::HBITMAP> {
-    // log_message(&format!("create_fallback_thumbnail: Creating fallback thumbnail of size {}x{}", size, size));
+impl Shell::IPreviewHandlerVisuals_Impl for PreviewHandler_Impl {
+    #[allow(non_snake_case)]
+    fn SetBackgroundColor(&self, color: COLORREF) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            let mut state = self.lock_state()?;
+            state.background_color = color;
+            update_preview_bitmap(&mut state)
+        })
+    }
+
+    #[allow(non_snake_case)]
+    fn SetFont(&self, _plf: *const Gdi::LOGFONTW) -> Result<()> {
+        // No text is drawn
+        Ok(())
+    }
 
-    // Use a hardcoded "broken file" SDR with red X pattern
-    const FALLBACK_SVG: &[u8] = b"<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 256 256\"><g><line stroke-width=\"2\" stroke=\"#ff0000\" y2=\"256\" x2=\"0\" y1=\"0\" x1=\"256\" fill=\"none\"/><line stroke-width=\"2\" y2=\"256\" x2=\"256\" y1=\"0\" x1=\"0\" stroke=\"#ff0000\" fill=\"none\"/></g></svg>";
+    #[allow(non_snake_case)]
+    fn SetTextColor(&self, _color: COLORREF) -> Result<()> {
+        // No text is drawn
+        Ok(())
+    }
+}
 
-    // Try to render the fallback SVG using our normal rendering pipeline
-    match render_sdr_to_hbitmap(FALLBACK_SVG, "", size, size) {
-        Ok(hbitmap) => {
-            log_message("create_fallback_thumbnail: Successfully created SVG-based fallback");
-            Ok(hbitmap)
-        },
-        Err(e) => {
-            log_message(&format!("create_fallback_thumbnail: SVG fallback failed: {:?}, creating bitmap fallback", e));
-            // If even the fallback SVG fails to render, create a simple black square as last resort
-            let bmi = Gdi::BITMAPINFO {
-                bmiHeader: Gdi::BITMAPINFOHEADER {
-                    biSize: std::mem::size_of::<Gdi::BITMAPINFOHEADER>() as u32,
-                    biWidth: size as i32,
-                    biHeight: -(size as i32), // Negative for top-down DIB
-                    biPlanes: 1,
-                    biBitCount: 32,
-                    biCompression: Gdi::BI_RGB.0 as u32,
-                    ..Default::default()
-                },
-                ..Default::default()
-            };
+impl System::Ole::IOleWindow_Impl for PreviewHandler_Impl {
+    #[allow(non_snake_case)]
+    fn GetWindow(&self) -> Result<HWND> {
+        ffi_guard!(Result<HWND>, {
+            let state = self.lock_state()?;
+            state.preview_hwnd.ok_or_else(|| Error::from(E_FAIL))
+        })
+    }
 
-            let mut dib_data: *mut std::ffi::c_void = std::ptr::null_mut();
-            let hbitmap_handle: Gdi::HBITMAP = unsafe {
-                Gdi::CreateDIBSection(None, &bmi, Gdi::DIB_RGB_COLORS, &mut dib_data, None, 0)
-            }?;
-            let hbitmap_guard = HBitmapGuard::new(hbitmap_handle);
+    #[allow(non_snake_case)]
+    fn ContextSensitiveHelp(&self, _fentermode: BOOL) -> Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+}
 
-            if !dib_data.is_null() {
-                // Fill with solid black (BGRA format: 0xFF000000)
-                let pixel_count = (size * size) as usize;
-                let buffer: &mut [u32] = unsafe {
-                    std::slice::from_raw_parts_mut(dib_data as *mut u32, pixel_count)
-                };
+impl System::Ole::IObjectWithSite_Impl for PreviewHandler_Impl {
+    #[allow(non_snake_case)]
+    fn SetSite(&self, punksite: Ref<'_, IUnknown>) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            self.lock_state()?.site = punksite.cloned();
+            Ok(())
+        })
+    }
 
-                // Solid black with full alpha
-                buffer.fill(0xFF000000);
+    #[allow(non_snake_case)]
+    fn GetSite(&self, riid: *const GUID, ppvsite: *mut *mut std::ffi::c_void) -> Result<()> {
+        ffi_guard!(Result<()>, {
+            if riid.is_null() || ppvsite.is_null() {
+                return Err(E_POINTER.into());
             }
+            unsafe { *ppvsite = std::ptr::null_mut() };
 
-            log_message("create_fallback_thumbnail: Successfully created bitmap-based fallback");
-            Ok(hbitmap_guard.release())
-        }
+            let state = self.lock_state()?;
+            match &state.site {
+                Some(site) => unsafe { site.query(riid, ppvsite) }.ok(),
+                None => Err(E_FAIL.into()),
+            }
+        })
     }
 }
 
@@ -426,19 +1881,22 @@ fn create_fallback_thumbnail(size: u32) -> Result<Gdi::HBITMAP> {
 // =================================================================
 
 #[implement(Com::IClassFactory)]
-struct ClassFactory;
+struct ClassFactory {
+    // Which of our COM objects this factory creates
+    clsid: GUID,
+}
 
-impl Default for ClassFactory {
-    fn default() -> Self {
+impl ClassFactory {
+    fn new(clsid: GUID) -> Self {
         dll_add_ref();
-        log_message("ClassFactory: Created new instance");
-        Self {}
+        log_trace!("ClassFactory: Created new instance");
+        Self { clsid }
     }
 }
 
 impl Drop for ClassFactory {
     fn drop(&mut self) {
-        log_message("ClassFactory: Dropping instance");
+        log_trace!("ClassFactory: Dropping instance");
         dll_release();
     }
 }
@@ -447,32 +1905,36 @@ impl Com::IClassFactory_Impl for ClassFactory_Impl {
     #[allow(non_snake_case)]
     fn CreateInstance(&self, punkouter: Ref<'_, IUnknown>, riid: *const GUID, ppvobject: *mut *mut std::ffi::c_void) -> Result<()> {
         ffi_guard!(Result<()>, {
-            // log_message(&format!("ClassFactory::CreateInstance: Entered. Requesting interface: {:?}", unsafe { *riid }));
+            // log_debug!("ClassFactory::CreateInstance: Entered. Requesting interface: {:?}", unsafe { *riid });
 
             // Safety checks for null pointers
             if riid.is_null() || ppvobject.is_null() {
-                log_message("ClassFactory::CreateInstance: Error - Null pointer passed");
+                log_error!("ClassFactory::CreateInstance: Error - Null pointer passed");
                 return Err(Error::new(E_POINTER, "Null pointer passed to CreateInstance"));
             }
 
             // We do not support aggregation.
             if !punkouter.is_null() {
-                log_message("ClassFactory::CreateInstance: Error - Aggregation not supported.");
+                log_error!("ClassFactory::CreateInstance: Error - Aggregation not supported.");
                 return Err(Error::new(CLASS_E_NOAGGREGATION, "Aggregation not supported"));
             }
 
-            log_message("ClassFactory::CreateInstance: Creating ThumbnailProvider instance");
-
-            // Create an instance of our ThumbnailProvider
-            let thumbnail_provider: IUnknown = ThumbnailProvider::default().into();
+            // Create an instance of the object this factory was made for
+            let instance: IUnknown = if self.clsid == CLSID_SDR_PREVIEW_HANDLER {
+                log_trace!("ClassFactory::CreateInstance: Creating PreviewHandler instance");
+                PreviewHandler::default().into()
+            } else {
+                log_trace!("ClassFactory::CreateInstance: Creating ThumbnailProvider instance");
+                ThumbnailProvider::default().into()
+            };
 
             // Query for the interface requested by the caller and return it.
-            let hr: HRESULT = unsafe { thumbnail_provider.query(&*riid, ppvobject) };
+            let hr: HRESULT = unsafe { instance.query(&*riid, ppvobject) };
 
             if hr.is_ok() {
                 Ok(())
             } else {
-                log_message(&format!("ClassFactory::CreateInstance: Error - Exiting with HRESULT: {:?}", hr));
+                log_debug!("ClassFactory::CreateInstance: Interface not supported, exiting with HRESULT: {:?}", hr);
                 Err(Error::new(hr, "Failed to query interface"))
             }
         })
@@ -482,10 +1944,10 @@ impl Com::IClassFactory_Impl for ClassFactory_Impl {
     fn LockServer(&self, flock: BOOL) -> Result<()> {
         ffi_guard!(Result<()>, {
             if flock.as_bool() {
-                log_message("ClassFactory::LockServer: Locking server (adding reference)");
+                log_trace!("ClassFactory::LockServer: Locking server (adding reference)");
                 dll_add_ref();
             } else {
-                log_message("ClassFactory::LockServer: Unlocking server (releasing reference)");
+                log_trace!("ClassFactory::LockServer: Unlocking server (releasing reference)");
                 dll_release();
             }
             Ok(())
@@ -501,22 +1963,40 @@ impl Com::IClassFactory_Impl for ClassFactory_Impl {
 static DLL_REFERENCES: AtomicU32 = AtomicU32::new(0);
 // A global handle to the DLL module instance - using Option for safer null checking
 static MODULE_HANDLE: AtomicPtr<std::ffi::c_void> = AtomicPtr::new(std::ptr::null_mut());
-// Global flag for whether to enable debug logging
-static ENABLE_DEBUG_LOGGING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
 // A global OnceLock for the log file path, initialized only once
 static LOG_FILE_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+// A global OnceLock for the always-on error log file path, initialized only once
+static ERROR_LOG_FILE_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+// A global OnceLock for the event source handle, registered only once and kept for the lifetime of the process
+static EVENT_SOURCE: OnceLock<Option<EventSourceHandle>> = OnceLock::new();
+// Serializes log writes and rollovers between the threads of this process
+static LOG_WRITE_LOCK: Mutex<()> = Mutex::new(());
+// A global OnceLock for the thumbnail cache directory, initialized only once
+static CACHE_DIR_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+// Running total of the cache directory size in bytes, CACHE_SIZE_UNKNOWN until the first write scanned it
+static CACHE_SIZE_ESTIMATE: AtomicU64 = AtomicU64::new(CACHE_SIZE_UNKNOWN);
+const CACHE_SIZE_UNKNOWN: u64 = u64::MAX;
+// Makes the temporary file names of concurrent cache writes unique
+static CACHE_TEMP_FILE_COUNTER: AtomicU32 = AtomicU32::new(0);
+// The registry settings, loaded on first use and swapped out by reload_config, see config()
+static CONFIG: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
+// Decoded badge images by path and size, see badge_pixels
+static BADGE_CACHE: Mutex<Vec<BadgeCacheEntry>> = Mutex::new(Vec::new());
+// The worker threads of renders that exceeded the render timeout and may still be running, see render_thumbnail_with_timeout
+static ABANDONED_RENDERS: Mutex<Vec<std::thread::JoinHandle<()>>> = Mutex::new(Vec::new());
 
 fn dll_add_ref() {
     let new_count = DLL_REFERENCES.fetch_add(1, Ordering::Relaxed) + 1;
-    log_message(&format!("DLL reference added. New count: {}", new_count));
+    log_trace!("DLL reference added. New count: {}", new_count);
 }
 fn dll_release() {
     let old_count = DLL_REFERENCES.fetch_sub(1, Ordering::Release);
-    log_message(&format!("DLL reference released. New count: {}", old_count - 1));
+    log_trace!("DLL reference released. New count: {}", old_count - 1);
 }
 
 /// Generic function to read registry values from HKEY_CLASSES_ROOT\.cu8
 /// Returns the value as a u32 if it exists and is a valid DWORD, otherwise returns None
+/// Like the other readers below this must not log, since Config::load uses it before the log level is known.
 fn read_sdr_registry_dword(value_name: &str) -> Option<u32> {
     let mut sdr_key: HKEY = HKEY::default();
     let result = unsafe {
@@ -553,46 +2033,286 @@ fn read_sdr_registry_dword(value_name: &str) -> Option<u32> {
         // Only return the value if it exists, is a DWORD, and has the expected size
         if query_result.is_ok() && value_type == REG_DWORD && value_size == std::mem::size_of::<u32>() as u32 {
             return Some(value);
-        } else if !query_result.is_ok() {
-            log_message(&format!("Registry read failed for '{}': {:?}", value_name, query_result));
         }
     } // Registry key automatically closed here by RegistryKeyGuard
 
     return None
 }
 
-// Checks registry for setting for whether to enable debug logging
-fn check_debug_logging_registry() {
-    // Note: We can't log here initially since logging might not be enabled yet
-    let enable_debug = match read_sdr_registry_dword("win_sdr_thumbs_enable_debug_log") {
-        Some(1) => true,  // Only enable debug logging if value exists and equals 1
-        _ => false,       // Default to disabled for any other case (missing, 0, or other values)
+/// Reads a string value from HKEY_CLASSES_ROOT\.cu8, the string counterpart of read_sdr_registry_dword
+/// Returns the value if it exists, is a REG_SZ or REG_EXPAND_SZ and isn't empty, otherwise returns None
+fn read_sdr_registry_string(value_name: &str) -> Option<String> {
+    let sdr_key_guard = open_sdr_registry_key()?;
+    query_registry_string(sdr_key_guard.0, value_name)
+}
+
+/// Reads a REG_MULTI_SZ value from HKEY_CLASSES_ROOT\.cu8, returning its non-empty entries.
+/// A missing value or one of another type yields no entries.
+fn read_sdr_registry_multi_string(value_name: &str) -> Vec<String> {
+    let Some(sdr_key_guard) = open_sdr_registry_key() else {
+        return Vec::new();
+    };
+    match query_registry_value(sdr_key_guard.0, value_name) {
+        Some((value_type, data)) if value_type == REG_MULTI_SZ => String::from_utf16_lossy(&wide_registry_data(&data))
+            .split('\0')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Opens HKEY_CLASSES_ROOT\.cu8 for reading, where all settings live
+fn open_sdr_registry_key() -> Option<RegistryKeyGuard> {
+    let mut sdr_key: HKEY = HKEY::default();
+    let result = unsafe { RegOpenKeyExW(HKEY_CLASSES_ROOT, w!(".cu8"), Some(0), KEY_READ, &mut sdr_key) };
+    result.is_ok().then(|| RegistryKeyGuard(sdr_key))
+}
+
+/// Reads a REG_SZ or REG_EXPAND_SZ value of an open key, trimmed. An empty `value_name` reads the (Default) value.
+/// Returns None if the value is missing, of another type or empty. Environment variables are not expanded.
+fn query_registry_string(key: HKEY, value_name: &str) -> Option<String> {
+    let (value_type, data) = query_registry_value(key, value_name)?;
+    if value_type != REG_SZ && value_type != REG_EXPAND_SZ {
+        return None;
+    }
+    let value = String::from_utf16_lossy(&wide_registry_data(&data));
+    let value = value.trim_end_matches('\0').trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Reads any value of an open key, returning its type and raw data
+fn query_registry_value(key: HKEY, value_name: &str) -> Option<(REG_VALUE_TYPE, Vec<u8>)> {
+    let wide_name = to_pcwstr(value_name);
+    let mut value_type = REG_VALUE_TYPE::default();
+    let mut value_size: u32 = 0;
+
+    // Ask for the size first, then read into a buffer of that size
+    let size_result = unsafe { RegQueryValueExW(key, PCWSTR(wide_name.as_ptr()), None, Some(&mut value_type), None, Some(&mut value_size)) };
+    if size_result.is_err() {
+        return None;
+    }
+
+    let mut data = vec![0u8; value_size as usize];
+    let query_result = unsafe {
+        RegQueryValueExW(key, PCWSTR(wide_name.as_ptr()), None, Some(&mut value_type), Some(data.as_mut_ptr()), Some(&mut value_size))
     };
+    if query_result.is_err() {
+        return None;
+    }
+    data.truncate(value_size as usize);
+    Some((value_type, data))
+}
+
+/// Reinterprets registry string data as UTF-16 units, the registry doesn't guarantee an even length
+fn wide_registry_data(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]])).collect()
+}
+
+/// All settings read from HKEY_CLASSES_ROOT\.cu8. They're loaded once per process on first use rather than in DllMain,
+/// so no registry access happens under the loader lock and the thumbnail hot path never touches the registry.
+/// Call the reload_config export to pick up changes.
+struct Config {
+    // Debug log level, 0 disables the debug log, see LogLevel
+    log_level: u32,
+    // Whether log lines also go to the Windows Application event log
+    log_to_event_log: bool,
+    // Size in bytes at which a log file is rolled over
+    log_max_size: u64,
+    // Whether to use the thumbnail disk cache
+    enable_thumbnail_cache: bool,
+    // Whether to fill transparent areas with a tint of the thumbnail's dominant color
+    enable_dominant_color_background: bool,
+    // Whether to round requested thumbnail sizes up to Explorer's standard cache sizes
+    enable_cache_size_rounding: bool,
+    // Whether to draw a diagnostic label onto each rendered thumbnail, never set in normal operation
+    enable_debug_overlay: bool,
+    // Whether WTSCF_FAST requests are answered from the disk cache only
+    enable_fast_context_cache_only: bool,
+    // Optional user-supplied image shown instead of the built-in fallback thumbnail
+    fallback_image_path: Option<PathBuf>,
+    // Milliseconds GetThumbnail waits for the renderer, 0 renders inline without a deadline
+    render_timeout_ms: u32,
+    // Transparent margin around thumbnails, in percent of the size per side
+    padding_percent: u32,
+    // Optional image drawn into the bottom right quadrant of thumbnails of files inside badge_folder
+    badge_image_path: Option<PathBuf>,
+    // Folder whose files (including subfolders) get the badge, no badge is drawn without it
+    badge_folder: Option<PathBuf>,
+    // Largest accepted input file in bytes
+    max_file_size: u64,
+    // Largest accepted thumbnail width or height
+    max_dimension: u32,
+}
+
+impl Default for Config {
+    /// The settings with none of the registry values present
+    fn default() -> Self {
+        Config {
+            log_level: 0,
+            log_to_event_log: false,
+            log_max_size: DEFAULT_LOG_MAX_SIZE_MB as u64 * 1024 * 1024,
+            enable_thumbnail_cache: false,
+            enable_dominant_color_background: false,
+            enable_cache_size_rounding: false,
+            enable_debug_overlay: false,
+            enable_fast_context_cache_only: false,
+            fallback_image_path: None,
+            render_timeout_ms: DEFAULT_RENDER_TIMEOUT_MS,
+            padding_percent: 0,
+            badge_image_path: None,
+            badge_folder: None,
+            max_file_size: DEFAULT_MAX_FILE_MB as u64 * 1024 * 1024,
+            max_dimension: MAX_DIMENSION_CEILING,
+        }
+    }
+}
+
+impl Config {
+    /// Reads all settings from the registry. Must not log, the logger takes its level from the result.
+    fn load() -> Self {
+        // win_sdr_thumbs_log_level takes 0 (off) to 5 (trace), see LogLevel. If it's missing, the older
+        // win_sdr_thumbs_enable_debug_log = 1 switch still enables everything.
+        let log_level = match read_sdr_registry_dword("win_sdr_thumbs_log_level") {
+            Some(level) => level.min(LogLevel::Trace as u32),
+            None => match read_sdr_registry_dword("win_sdr_thumbs_enable_debug_log") {
+                Some(1) => LogLevel::Trace as u32,  // Only enable debug logging if value exists and equals 1
+                _ => 0,                             // Default to disabled for any other case (missing, 0, or other values)
+            },
+        };
+
+        // Each flag is only enabled if its value exists and equals 1
+        let flag = |value_name: &str| matches!(read_sdr_registry_dword(value_name), Some(1));
+
+        // Zero or a missing value keeps the default size cap
+        let log_max_size_mb = match read_sdr_registry_dword("win_sdr_thumbs_log_max_size_mb") {
+            Some(value) if value > 0 => value,
+            _ => DEFAULT_LOG_MAX_SIZE_MB,
+        };
+
+        // Zero or a missing value keeps the default file size cap
+        let max_file_mb = match read_sdr_registry_dword("win_sdr_thumbs_max_file_mb") {
+            Some(value) if value > 0 => value.min(MAX_FILE_MB_CEILING),
+            _ => DEFAULT_MAX_FILE_MB,
+        };
+
+        // Unlike the flags this is a value, zero or a missing value keeps the default
+        let max_dimension = match read_sdr_registry_dword("win_sdr_thumbs_max_dimension") {
+            Some(value) if value > 0 => value.min(MAX_DIMENSION_CEILING),
+            _ => MAX_DIMENSION_CEILING,
+        };
+
+        Config {
+            log_level,
+            log_to_event_log: flag("win_sdr_thumbs_log_to_eventlog"),
+            log_max_size: log_max_size_mb as u64 * 1024 * 1024,
+            enable_thumbnail_cache: flag("win_sdr_thumbs_enable_cache"),
+            enable_dominant_color_background: flag("win_sdr_thumbs_dominant_color_background"),
+            enable_cache_size_rounding: flag("win_sdr_thumbs_round_to_cache_size"),
+            enable_debug_overlay: flag("win_sdr_thumbs_debug_overlay"),
+            enable_fast_context_cache_only: flag("win_sdr_thumbs_fast_context_cache_only"),
+            fallback_image_path: read_sdr_registry_string("win_sdr_thumbs_fallback_image_path").map(PathBuf::from),
+            // A missing value keeps the default, zero turns the timeout off
+            render_timeout_ms: read_sdr_registry_dword("win_sdr_thumbs_render_timeout_ms").unwrap_or(DEFAULT_RENDER_TIMEOUT_MS),
+            padding_percent: read_sdr_registry_dword("win_sdr_thumbs_padding_percent").unwrap_or(0).min(MAX_PADDING_PERCENT),
+            badge_image_path: read_sdr_registry_string("win_sdr_thumbs_badge_image_path").map(PathBuf::from),
+            badge_folder: read_sdr_registry_string("win_sdr_thumbs_badge_folder").map(PathBuf::from),
+            max_file_size: max_file_mb as u64 * 1024 * 1024,
+            max_dimension,
+        }
+    }
+
+    /// Logs the settings that differ from the defaults. Called once a config is in place, so the logger can use it.
+    fn log_settings(&self) {
+        if self.log_level > 0 {
+            log_info!("Debug logging ENABLED via registry at level {}", self.log_level);
+        }
+
+        if self.enable_thumbnail_cache {
+            log_info!("Thumbnail disk cache ENABLED via registry");
+        }
+        if self.enable_dominant_color_background {
+            log_info!("Dominant color background ENABLED via registry");
+        }
+        if self.enable_cache_size_rounding {
+            log_info!("Rounding to standard thumbnail cache sizes ENABLED via registry");
+        }
+        if self.enable_fast_context_cache_only {
+            log_info!("Cache-only answers for fast thumbnail requests ENABLED via registry");
+        }
+        if self.enable_debug_overlay {
+            log_warn!("Debug overlay ENABLED via registry, thumbnails will show render diagnostics");
+        }
+        if let Some(path) = &self.fallback_image_path {
+            log_info!("Custom fallback image set to '{}' via registry", path.display());
+        }
+        if self.padding_percent > 0 {
+            log_info!("Thumbnail padding set to {}% via registry (at most {}%)", self.padding_percent, MAX_PADDING_PERCENT);
+        }
+        match (&self.badge_image_path, &self.badge_folder) {
+            (Some(path), Some(folder)) => log_info!("Badge image set to '{}' for files in '{}' via registry", path.display(), folder.display()),
+            (Some(_), None) => log_warn!("Badge image is set but win_sdr_thumbs_badge_folder is not, no badge will be drawn"),
+            _ => {}
+        }
+        if self.render_timeout_ms != DEFAULT_RENDER_TIMEOUT_MS {
+            log_info!("Render timeout set to {} ms via registry", self.render_timeout_ms);
+        }
+        if self.max_file_size != DEFAULT_MAX_FILE_MB as u64 * 1024 * 1024 {
+            log_info!("Maximum file size set to {} MiB via registry", self.max_file_size / (1024 * 1024));
+        }
+        if self.max_dimension != MAX_DIMENSION_CEILING {
+            log_info!("Maximum dimension set to {} via registry", self.max_dimension);
+        }
+    }
+}
 
-    ENABLE_DEBUG_LOGGING.store(enable_debug, Ordering::Relaxed);
+/// Returns the current settings, reading them from the registry on first use.
+/// The returned snapshot stays valid even if reload_config swaps in new settings meanwhile.
+fn config() -> Arc<Config> {
+    let mut newly_loaded = false;
+    let config_lock = CONFIG.get_or_init(|| {
+        newly_loaded = true;
+        RwLock::new(Arc::new(Config::load()))
+    });
+    let config = Arc::clone(&config_lock.read().unwrap_or_else(|poisoned| poisoned.into_inner()));
 
-    // Now we can log since the flag is set
-    if enable_debug {
-        log_message("Debug logging ENABLED via registry");
+    // Logging inside get_or_init would re-enter it through the logger, so this waits until the config is in place
+    if newly_loaded {
+        config.log_settings();
     }
+    config
+}
+
+/// Replaces the registry settings with the defaults for the rest of the process, so the output doesn't depend on how
+/// the machine is configured, e.g. for the golden image tests. `reload_config` reads the registry again.
+pub fn use_default_settings() {
+    let default_config = Arc::new(Config::default());
+    let config_lock = CONFIG.get_or_init(|| RwLock::new(Arc::clone(&default_config)));
+    *config_lock.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = default_config;
+    BADGE_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
 }
 
 // This is our thumbnail provider's unique Class ID (CLSID).
 // Use a new GUID for your own projects!
 const CLSID_SDR_THUMBNAIL_PROVIDER: GUID = GUID::from_u128(0xadfa4c4b_5cfb_4335_be68_d4d60f2ab71f);
+// Class ID of the preview pane handler, a separate COM object from the thumbnail provider
+const CLSID_SDR_PREVIEW_HANDLER: GUID = GUID::from_u128(0xbed65396_291b_4dda_8668_eb6ed19248bd);
 
 #[no_mangle]
 #[allow(non_snake_case)]
-extern "system" fn DllMain(hinst_dll: HMODULE, fdw_reason: u32, _lpv_reserved: *const std::ffi::c_void) -> BOOL {
+extern "system" fn DllMain(hinst_dll: HMODULE, fdw_reason: u32, lpv_reserved: *const std::ffi::c_void) -> BOOL {
     ffi_guard!(BOOL, {
+        // Runs under the loader lock, so only bookkeeping here. The registry settings are read on first use, see config(),
+        // and logging is left out since the logger loads them and touches the file system.
         if fdw_reason == System::SystemServices::DLL_PROCESS_ATTACH {
             MODULE_HANDLE.store(hinst_dll.0 as *mut _, Ordering::Release);
-            // Check registry for debug logging preference once at startup
-            check_debug_logging_registry();
-
-            log_message("DllMain: DLL_PROCESS_ATTACH completed. DLL is loaded and initialized.");
-        } else if fdw_reason == System::SystemServices::DLL_PROCESS_DETACH {
-            log_message("DllMain: DLL_PROCESS_DETACH received. DLL is unloading.");
+            // We don't need the thread attach and detach notifications
+            let _ = unsafe { System::LibraryLoader::DisableThreadLibraryCalls(hinst_dll) };
+        } else if fdw_reason == System::SystemServices::DLL_PROCESS_DETACH && lpv_reserved.is_null() {
+            // Unloaded by FreeLibrary (e.g. once DllCanUnloadNow allowed it) while the process keeps running. A later load
+            // would fail to register the preview window class while the stale one is still around.
+            unregister_preview_window_class();
         }
         true
     })
@@ -602,27 +2322,25 @@ extern "system" fn DllMain(hinst_dll: HMODULE, fdw_reason: u32, _lpv_reserved: *
 #[allow(non_snake_case)]
 pub extern "system" fn DllGetClassObject(rclsid: *const GUID, riid: *const GUID, ppv: *mut *mut std::ffi::c_void) -> HRESULT {
     ffi_guard!(HRESULT, {
-        // Check registry settings at entry point in case they changed since DLL load
-        check_debug_logging_registry();
-
-        log_message("DllGetClassObject: Entered");
+        log_trace!("DllGetClassObject: Entered");
 
         // Safety checks for null pointers
         if rclsid.is_null() || riid.is_null() || ppv.is_null() {
-            log_message("DllGetClassObject: Error - Null pointer passed");
+            log_error!("DllGetClassObject: Error - Null pointer passed");
             return E_POINTER;
         }
 
-        // Check if the caller is asking for our specific class.
-        if unsafe { *rclsid } != CLSID_SDR_THUMBNAIL_PROVIDER {
-            log_message(&format!("DllGetClassObject: Error - CLSID mismatch. Requested: {:?}, Expected: {:?}", unsafe { *rclsid }, CLSID_SDR_THUMBNAIL_PROVIDER));
+        // Check if the caller is asking for one of our classes.
+        let clsid = unsafe { *rclsid };
+        if clsid != CLSID_SDR_THUMBNAIL_PROVIDER && clsid != CLSID_SDR_PREVIEW_HANDLER {
+            log_debug!("DllGetClassObject: Not one of our classes. Requested: {:?}, Expected: {:?} or {:?}", clsid, CLSID_SDR_THUMBNAIL_PROVIDER, CLSID_SDR_PREVIEW_HANDLER);
             return CLASS_E_CLASSNOTAVAILABLE;
         }
 
-        log_message("DllGetClassObject: Creating class factory for SDR Thumbnail Provider");
+        log_trace!("DllGetClassObject: Creating class factory for {:?}", clsid);
 
         // Create our class factory.
-        let factory: Com::IClassFactory = ClassFactory::default().into();
+        let factory: Com::IClassFactory = ClassFactory::new(clsid).into();
 
         // Query for the interface the caller wants (usually IClassFactory) and return it.
         let hr: HRESULT = unsafe { factory.query(riid, ppv) };
@@ -630,12 +2348,12 @@ pub extern "system" fn DllGetClassObject(rclsid: *const GUID, riid: *const GUID,
         // The factory variable will automatically drop here, releasing our local reference.
         // The caller retains their reference from the query() call.
 
-        // log_message(&format!("DllGetClassObject: Exiting with HRESULT: {:?}", hr));
+        // log_debug!("DllGetClassObject: Exiting with HRESULT: {:?}", hr);
         // Log only if it's an error
         if hr.is_err() {
-            log_message(&format!("DllGetClassObject: Error - Exiting with HRESULT: {:?}", hr));
+            log_debug!("DllGetClassObject: Interface not supported, exiting with HRESULT: {:?}", hr);
         } else {
-            // log_message("DllGetClassObject: Succeeded.");
+            // log_debug!("DllGetClassObject: Succeeded.");
         }
 
         hr
@@ -648,11 +2366,17 @@ pub extern "system" fn DllCanUnloadNow() -> HRESULT {
     ffi_guard!(HRESULT, {
         let ref_count = DLL_REFERENCES.load(Ordering::Acquire);
 
-        if ref_count == 0 {
-            log_message("DllCanUnloadNow: Returning S_OK - DLL can be unloaded");
+        // A timed out render may still be running code from this DLL without holding a reference. If a render holds the
+        // lock right now, answer S_FALSE and let the caller ask again later
+        let render_running = ABANDONED_RENDERS.try_lock().map(|mut abandoned_renders| !reap_abandoned_renders(&mut abandoned_renders)).unwrap_or(true);
+        if render_running {
+            log_trace!("DllCanUnloadNow: Returning S_FALSE - a timed out render is still running");
+            S_FALSE
+        } else if ref_count == 0 {
+            log_trace!("DllCanUnloadNow: Returning S_OK - DLL can be unloaded");
             S_OK
         } else {
-            log_message(&format!("DllCanUnloadNow: Returning S_FALSE - DLL still has {} active references", ref_count));
+            log_trace!("DllCanUnloadNow: Returning S_FALSE - DLL still has {} active references", ref_count);
             S_FALSE
         }
     })
@@ -668,62 +2392,190 @@ fn to_pcwstr(s: &str) -> Vec<u16> {
     OsStr::new(s).encode_wide().chain(std::iter::once(0)).collect()
 }
 
-fn create_registry_keys() -> Result<()> {
-    log_message("create_registry_keys: Starting registry key creation");
+/// Where the handler gets registered
+#[derive(Clone, Copy, Debug)]
+enum RegistrationScope {
+    // HKEY_CLASSES_ROOT and HKEY_LOCAL_MACHINE, for all users, requires admin rights
+    Machine,
+    // HKEY_CURRENT_USER\Software\Classes, for the current user only, works without elevation
+    User,
+}
+
+impl RegistrationScope {
+    /// The hive and path prefix under which the class keys (CLSID, file extensions) live
+    fn classes_root(self) -> (HKEY, &'static str) {
+        match self {
+            RegistrationScope::Machine => (HKEY_CLASSES_ROOT, ""),
+            RegistrationScope::User => (HKEY_CURRENT_USER, "Software\\Classes\\"),
+        }
+    }
+
+    /// The hive holding the shell's list of preview handlers
+    fn software_hive(self) -> HKEY {
+        match self {
+            RegistrationScope::Machine => HKEY_LOCAL_MACHINE,
+            RegistrationScope::User => HKEY_CURRENT_USER,
+        }
+    }
+}
+
+fn create_registry_keys(scope: RegistrationScope) -> Result<()> {
+    log_info!("create_registry_keys: Starting registry key creation for scope {:?}", scope);
+    let (classes_hive, classes_prefix) = scope.classes_root();
 
     let clsid_string = format!("{{{CLSID_SDR_THUMBNAIL_PROVIDER:?}}}");
     let dll_path = get_dll_path()?;
-    log_message(&format!("create_registry_keys: Using CLSID: {} and DLL path: {}", clsid_string, dll_path));
+    log_info!("create_registry_keys: Using CLSID: {} and DLL path: {}", clsid_string, dll_path);
 
     // Create CLSID\{our-clsid}
-    // log_message("create_registry_keys: Creating CLSID root key");
-    let clsid_root_key = RegistryKeyGuard::create_root_key(HKEY_CLASSES_ROOT, &w!("CLSID"))?;
+    // log_debug!("create_registry_keys: Creating CLSID root key");
+    let clsid_root_key = RegistryKeyGuard::create_root_key(classes_hive, &PCWSTR(to_pcwstr(&format!("{}CLSID", classes_prefix)).as_ptr()))?;
 
-    log_message("create_registry_keys: Creating CLSID subkey and setting description");
+    log_debug!("create_registry_keys: Creating CLSID subkey and setting description");
     let clsid_key = clsid_root_key.create_subkey(&PCWSTR(to_pcwstr(&clsid_string).as_ptr()))?;
     clsid_key.set_string_value("", "SDR Thumbnail Provider (Rust)")?;
 
     // Create CLSID\{our-clsid}\InprocServer32
-    log_message("create_registry_keys: Creating InprocServer32 key");
+    log_debug!("create_registry_keys: Creating InprocServer32 key");
     let inproc_key = clsid_key.create_subkey(&w!("InprocServer32"))?;
     inproc_key.set_string_value("", &dll_path)?;
     inproc_key.set_string_value("ThreadingModel", "Apartment")?;
 
-    // All supported file types
-    const ALL_FILE_EXTENSIONS: &[PCWSTR] = &[
-        w!(".cu4"),
-        w!(".cs4"),
-        w!(".cu8"), w!(".complex16u"),
-        w!(".cs8"), w!(".complex16s"),
-        w!(".cu12"),
-        w!(".cs12"),
-        w!(".cu16"),
-        w!(".cs16"),
-        w!(".cu32"),
-        w!(".cs32"),
-        w!(".cu64"),
-        w!(".cs64"),
-        w!(".cf32"), w!(".cfile"), w!(".complex"),
-        w!(".cf64"),
-        w!(".sigmf"),
-    ];
+    // The shell only uses IInitializeWithFile when the handler opts out of process isolation. That runs the renderer
+    // on untrusted files inside explorer.exe instead of the isolated surrogate, so it's an opt-in and off by default.
+    if is_process_isolation_disabled() {
+        log_warn!("create_registry_keys: Opting out of process isolation via registry");
+        clsid_key.set_dword_value("DisableProcessIsolation", 1)?;
+    } else {
+        // Also clear the opt-out from an earlier registration
+        clsid_key.delete_value("DisableProcessIsolation")?;
+    }
+
+    // Create CLSID\{preview-clsid}, hosted by the system's prevhost.exe surrogate
+    log_debug!("create_registry_keys: Creating preview handler CLSID subkey");
+    let preview_clsid_string = format!("{{{CLSID_SDR_PREVIEW_HANDLER:?}}}");
+    let preview_clsid_key = clsid_root_key.create_subkey(&PCWSTR(to_pcwstr(&preview_clsid_string).as_ptr()))?;
+    preview_clsid_key.set_string_value("", "SDR Preview Handler (Rust)")?;
+    preview_clsid_key.set_string_value("DisplayName", "SDR Preview Handler")?;
+    preview_clsid_key.set_string_value("AppID", PREVHOST_APPID)?;
+    let preview_inproc_key = preview_clsid_key.create_subkey(&w!("InprocServer32"))?;
+    preview_inproc_key.set_string_value("", &dll_path)?;
+    preview_inproc_key.set_string_value("ThreadingModel", "Apartment")?;
+
+    // The shell only offers preview handlers that are also listed here
+    let preview_handlers_key = RegistryKeyGuard::create_root_key(scope.software_hive(), &w!("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\PreviewHandlers"))?;
+    preview_handlers_key.set_string_value(&preview_clsid_string, "SDR Preview Handler")?;
 
     // Associate with file extensions
-    log_message("create_registry_keys: Associating with file extensions");
-    for fext in ALL_FILE_EXTENSIONS {
-        let file_root_key = RegistryKeyGuard(HKEY_CLASSES_ROOT).create_subkey(fext)?;
+    log_debug!("create_registry_keys: Associating with file extensions");
+    let handler_guids = get_shellex_handler_guids();
+    for fext in SDR_FILE_EXTENSIONS {
+        if !is_extension_enabled(fext) {
+            // Also clear a registration from before the extension was turned off, but not another handler's slots
+            log_info!("create_registry_keys: Skipping disabled extension {}", fext);
+            if let Err(e) = delete_own_shellex_slots(classes_hive, &format!("{}{}\\shellex", classes_prefix, fext)) {
+                log_warn!("create_registry_keys: Failed to clear the earlier registration of {}: {:?}", fext, e);
+            }
+            continue;
+        }
+        let file_root_key = RegistryKeyGuard::create_root_key(classes_hive, &PCWSTR(to_pcwstr(&format!("{}{}", classes_prefix, fext)).as_ptr()))?;
         let file_shellex_key = file_root_key.create_subkey(&w!("shellex"))?;
-        let file_handler_key = file_shellex_key.create_subkey(&w!("{E357FCCD-A995-4576-B01F-234630154E96}"))?;
-        file_handler_key.set_string_value("", &clsid_string)?;
+        for handler_guid in &handler_guids {
+            let file_handler_key = file_shellex_key.create_subkey(&PCWSTR(to_pcwstr(handler_guid).as_ptr()))?;
+            file_handler_key.set_string_value("", &clsid_string)?;
+        }
+        let file_preview_key = file_shellex_key.create_subkey(&PCWSTR(to_pcwstr(PREVIEW_HANDLER_GUID).as_ptr()))?;
+        file_preview_key.set_string_value("", &preview_clsid_string)?;
+    }
+
+    // The event source is machine-wide only. It's optional, so a failure doesn't fail the registration.
+    if matches!(scope, RegistrationScope::Machine) {
+        if let Err(e) = register_event_source() {
+            log_warn!("create_registry_keys: Failed to register event source: {:?}", e);
+        }
     }
 
-    // log_message("create_registry_keys: Notifying shell of association changes");
+    // log_debug!("create_registry_keys: Notifying shell of association changes");
     unsafe { Shell::SHChangeNotify(Shell::SHCNE_ASSOCCHANGED, Shell::SHCNF_IDLIST, None, None) };
 
-    // log_message("create_registry_keys: Successfully completed registry key creation");
+    // log_debug!("create_registry_keys: Successfully completed registry key creation");
     Ok(())
 }
 
+/// All supported file types, with the leading dot
+pub const SDR_FILE_EXTENSIONS: &[&str] = &[
+    ".cu4",
+    ".cs4",
+    ".cu8", ".complex16u",
+    ".cs8", ".complex16s",
+    ".cu12",
+    ".cs12",
+    ".cu16",
+    ".cs16",
+    ".cu32",
+    ".cs32",
+    ".cu64",
+    ".cs64",
+    ".cf32", ".cfile", ".complex",
+    ".cf64",
+    ".sigmf",
+];
+
+/// Whether thumbnails and previews should be registered for this extension. Each extension can be turned off with a
+/// DWORD named after it, e.g. HKEY_CLASSES_ROOT\.cu8\win_sdr_thumbs_handle_cs16 = 0, before (re-)registering.
+/// Like every setting this is read from .cu8, one of the keys registration writes to, so that key keeps the values
+/// even when .cu8 itself is turned off. Only the shellex slots are touched, never the settings next to them.
+/// Unregistering always removes the entries of all extensions, so it cleans up whichever were written.
+fn is_extension_enabled(fext: &str) -> bool {
+    let value_name = format!("win_sdr_thumbs_handle_{}", fext.trim_start_matches('.'));
+    read_sdr_registry_dword(&value_name) != Some(0)
+}
+
+/// Whether the thumbnail provider should be registered to run inside the calling process (usually explorer.exe)
+/// rather than the isolated surrogate. Only set with HKEY_CLASSES_ROOT\.cu8\win_sdr_thumbs_disable_process_isolation = 1
+/// before (re-)registering. A crash in the renderer then takes Explorer down with it, so leave this off unless needed.
+fn is_process_isolation_disabled() -> bool {
+    read_sdr_registry_dword("win_sdr_thumbs_disable_process_isolation") == Some(1)
+}
+
+// The shellex slot Windows uses to look up a file type's IThumbnailProvider
+const THUMBNAIL_HANDLER_GUID: &str = "{E357FCCD-A995-4576-B01F-234630154E96}";
+// The shellex slot Windows uses to look up a file type's IPreviewHandler
+const PREVIEW_HANDLER_GUID: &str = "{8895b1c6-b41f-4c1c-a562-0d564250836f}";
+
+// AppID of prevhost.exe, the surrogate process the shell runs preview handlers in
+#[cfg(target_pointer_width = "64")]
+const PREVHOST_APPID: &str = "{6d2b5079-2f0b-48dd-ab7f-97cec514d30b}";
+// The 32-bit surrogate, for a 32-bit DLL on 64-bit Windows
+#[cfg(not(target_pointer_width = "64"))]
+const PREVHOST_APPID: &str = "{534a1e02-d58f-44f0-b58b-36cbed287c7c}";
+
+/// Returns the shellex handler slots to register under for each file type.
+/// This is always the standard thumbnail handler, plus any GUIDs listed in the optional REG_MULTI_SZ value
+/// HKEY_CLASSES_ROOT\.cu8\win_sdr_thumbs_extra_handler_guids, e.g. to take over a slot another extension uses.
+/// Use with care: a listed slot replaces whatever handler another extension registered there. Unregistering only
+/// removes slots that still point at our CLSID, see delete_own_shellex_slots.
+fn get_shellex_handler_guids() -> Vec<String> {
+    let mut handler_guids = vec![THUMBNAIL_HANDLER_GUID.to_string()];
+
+    for extra_guid in read_sdr_registry_multi_string("win_sdr_thumbs_extra_handler_guids") {
+        // Only accept well-formed GUIDs, normalized to the same braced format as our CLSID
+        let inner = extra_guid.trim().trim_start_matches('{').trim_end_matches('}');
+        match GUID::try_from(inner) {
+            Ok(guid) => {
+                let guid_string = format!("{{{guid:?}}}");
+                if !handler_guids.iter().any(|existing| existing.eq_ignore_ascii_case(&guid_string)) {
+                    log_info!("get_shellex_handler_guids: Co-registering under extra handler slot {}", guid_string);
+                    handler_guids.push(guid_string);
+                }
+            }
+            Err(_) => log_warn!("get_shellex_handler_guids: Ignoring invalid GUID '{}'", extra_guid),
+        }
+    }
+
+    handler_guids
+}
+
 fn get_dll_path() -> Result<String> {
     let handle_ptr: *mut std::ffi::c_void = MODULE_HANDLE.load(Ordering::Acquire);
 
@@ -833,19 +2685,47 @@ impl RegistryKeyGuard {
         }
         Ok(())
     }
+
+    /// Sets a REG_DWORD value for this registry key.
+    fn set_dword_value(&self, name: &str, value: u32) -> Result<()> {
+        let wide_name = to_pcwstr(name);
+
+        unsafe {
+            RegSetValueExW(
+                self.0,
+                PCWSTR(wide_name.as_ptr()),
+                None,
+                REG_DWORD,
+                Some(&value.to_le_bytes()[..]),
+            ).ok()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes a value from this registry key. A missing value counts as success.
+    fn delete_value(&self, name: &str) -> Result<()> {
+        let wide_name = to_pcwstr(name);
+        let result = unsafe { RegDeleteValueW(self.0, PCWSTR(wide_name.as_ptr())) };
+        if result != ERROR_SUCCESS && result != ERROR_FILE_NOT_FOUND {
+            return Err(Error::new(result.into(), "Registry value deletion failed"));
+        }
+        Ok(())
+    }
 }
 
-fn delete_registry_keys() -> Result<()> {
-    log_message("delete_registry_keys: Starting registry key deletion");
+fn delete_registry_keys(scope: RegistrationScope) -> Result<()> {
+    log_info!("delete_registry_keys: Starting registry key deletion for scope {:?}", scope);
+    let (classes_hive, classes_prefix) = scope.classes_root();
 
     let clsid_string = format!("{{{CLSID_SDR_THUMBNAIL_PROVIDER:?}}}");
-    log_message(&format!("delete_registry_keys: Deleting keys for CLSID: {}", clsid_string));
+    log_info!("delete_registry_keys: Deleting keys for CLSID: {}", clsid_string);
     // Track if we encountered any real errors (not just "not found")
     let mut first_real_error: Option<Error> = None;
 
     // Helper closure for robust key deletion
-    let mut delete_key_with_error_tracking = |key_path: PCWSTR| {
-        let result = unsafe { RegDeleteKeyExW(HKEY_CLASSES_ROOT, key_path, WRITE_FLAGS.0, Some(0)) };
+    let mut delete_key_with_error_tracking = |key_path: &str| {
+        let full_path = to_pcwstr(&format!("{}{}", classes_prefix, key_path));
+        let result = unsafe { RegDeleteKeyExW(classes_hive, PCWSTR(full_path.as_ptr()), WRITE_FLAGS.0, Some(0)) };
         if result == ERROR_SUCCESS || result == ERROR_FILE_NOT_FOUND {
             // Success or key already gone - both fine for uninstall
         } else {
@@ -857,32 +2737,33 @@ fn delete_registry_keys() -> Result<()> {
     };
 
     // Try to delete all keys, tracking errors but not stopping
-    let inproc_path = to_pcwstr(&format!("CLSID\\{}\\InprocServer32", clsid_string));
-    delete_key_with_error_tracking(PCWSTR(inproc_path.as_ptr()));
-
-    let clsid_path = to_pcwstr(&format!("CLSID\\{}", clsid_string));
-    delete_key_with_error_tracking(PCWSTR(clsid_path.as_ptr()));
-
-    // All supported file types
-    const ALL_FILE_EXTENSIONS: &[PCWSTR] = &[
-        w!(".cu4\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs4\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cu8\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"), w!(".complex16u\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs8\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"), w!(".complex16s\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cu12\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs12\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cu16\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs16\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cu32\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs32\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cu64\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cs64\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cf32\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"), w!(".cfile\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"), w!(".complex\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".cf64\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-        w!(".sigmf\\shellex\\{E357FCCD-A995-4576-B01F-234630154E96}"),
-    ];
-    for &fext in ALL_FILE_EXTENSIONS {
-        delete_key_with_error_tracking(fext);
+    delete_key_with_error_tracking(&format!("CLSID\\{}\\InprocServer32", clsid_string));
+    delete_key_with_error_tracking(&format!("CLSID\\{}", clsid_string));
+
+    let preview_clsid_string = format!("{{{CLSID_SDR_PREVIEW_HANDLER:?}}}");
+    delete_key_with_error_tracking(&format!("CLSID\\{}\\InprocServer32", preview_clsid_string));
+    delete_key_with_error_tracking(&format!("CLSID\\{}", preview_clsid_string));
+
+    // Remove the handler keys from all supported file types, including any co-registered slots
+    for fext in SDR_FILE_EXTENSIONS {
+        if let Err(e) = delete_own_shellex_slots(classes_hive, &format!("{}{}\\shellex", classes_prefix, fext)) {
+            if first_real_error.is_none() {
+                first_real_error = Some(e);
+            }
+        }
+    }
+
+    // Remove the preview handler from the shell's list, a missing value is fine as well
+    let preview_value_name = to_pcwstr(&preview_clsid_string);
+    let result = unsafe { RegDeleteKeyValueW(scope.software_hive(), w!("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\PreviewHandlers"), PCWSTR(preview_value_name.as_ptr())) };
+    if result != ERROR_SUCCESS && result != ERROR_FILE_NOT_FOUND && first_real_error.is_none() {
+        first_real_error = Some(Error::new(result.into(), "Registry value deletion failed"));
+    }
+
+    if matches!(scope, RegistrationScope::Machine) {
+        if let Err(e) = unregister_event_source() {
+            log_warn!("delete_registry_keys: Failed to remove event source: {:?}", e);
+        }
     }
 
     // Always notify of association changes, even if some deletions failed
@@ -895,19 +2776,72 @@ fn delete_registry_keys() -> Result<()> {
     }
 }
 
+/// Deletes the handler slots under a file type's shellex key whose (Default) value is one of our CLSIDs.
+/// The slots are found by enumerating the key rather than from the current win_sdr_thumbs_extra_handler_guids,
+/// so slots written under an earlier list are removed too, and a slot another handler took over since is left alone.
+/// A missing shellex key counts as success.
+fn delete_own_shellex_slots(classes_hive: HKEY, shellex_path: &str) -> Result<()> {
+    let own_clsids = [format!("{{{CLSID_SDR_THUMBNAIL_PROVIDER:?}}}"), format!("{{{CLSID_SDR_PREVIEW_HANDLER:?}}}")];
+
+    let wide_shellex_path = to_pcwstr(shellex_path);
+    let mut shellex_key = HKEY::default();
+    let result = unsafe { RegOpenKeyExW(classes_hive, PCWSTR(wide_shellex_path.as_ptr()), Some(0), KEY_READ, &mut shellex_key) };
+    if result == ERROR_FILE_NOT_FOUND {
+        return Ok(());
+    }
+    result.ok()?;
+    let shellex_key_guard = RegistryKeyGuard(shellex_key);
+
+    // Collect the slot names first, deleting while enumerating would shift the indices
+    let mut slot_names: Vec<String> = Vec::new();
+    for index in 0.. {
+        let mut name = [0u16; 256]; // Registry key names are at most 255 characters
+        let mut name_len = name.len() as u32;
+        let result = unsafe {
+            RegEnumKeyExW(shellex_key_guard.0, index, Some(PWSTR(name.as_mut_ptr())), &mut name_len, None, None, None, None)
+        };
+        if result != ERROR_SUCCESS {
+            break;
+        }
+        slot_names.push(String::from_utf16_lossy(&name[..name_len as usize]));
+    }
+
+    for slot_name in slot_names {
+        let wide_slot_name = to_pcwstr(&slot_name);
+        let mut slot_key = HKEY::default();
+        if unsafe { RegOpenKeyExW(shellex_key_guard.0, PCWSTR(wide_slot_name.as_ptr()), Some(0), KEY_READ, &mut slot_key) }.is_err() {
+            continue;
+        }
+        let handler_clsid = {
+            let slot_key_guard = RegistryKeyGuard(slot_key);
+            query_registry_string(slot_key_guard.0, "")
+        }; // Slot key closed here, before it gets deleted
+        if !handler_clsid.as_ref().is_some_and(|handler| own_clsids.iter().any(|own| own.eq_ignore_ascii_case(handler))) {
+            log_debug!("delete_own_shellex_slots: Leaving {}\\{}, it points at {:?}", shellex_path, slot_name, handler_clsid);
+            continue;
+        }
+
+        let result = unsafe { RegDeleteKeyExW(shellex_key_guard.0, PCWSTR(wide_slot_name.as_ptr()), WRITE_FLAGS.0, Some(0)) };
+        if result != ERROR_SUCCESS && result != ERROR_FILE_NOT_FOUND {
+            return Err(Error::new(result.into(), "Registry key deletion failed"));
+        }
+    }
+    Ok(())
+}
+
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "system" fn DllRegisterServer() -> HRESULT {
     ffi_guard!(HRESULT, {
-        // log_message("DllRegisterServer: Starting registration");
-        match create_registry_keys() {
+        // log_debug!("DllRegisterServer: Starting registration");
+        match create_registry_keys(RegistrationScope::Machine) {
             Ok(_) => {
-                log_message("DllRegisterServer: Registration succeeded");
+                log_info!("DllRegisterServer: Registration succeeded");
                 S_OK
             },
             Err(e) => {
-                log_message(&format!("DllRegisterServer: Registration failed: {:?}", e));
+                log_error!("DllRegisterServer: Registration failed: {:?}", e);
                 E_FAIL
             },
         }
@@ -918,20 +2852,127 @@ pub extern "system" fn DllRegisterServer() -> HRESULT {
 #[allow(non_snake_case)]
 pub extern "system" fn DllUnregisterServer() -> HRESULT {
     ffi_guard!(HRESULT, {
-        // log_message("DllUnregisterServer: Starting unregistration");
-        match delete_registry_keys() {
+        // log_debug!("DllUnregisterServer: Starting unregistration");
+        match delete_registry_keys(RegistrationScope::Machine) {
+            Ok(_) => {
+                log_info!("DllUnregisterServer: Unregistration succeeded");
+                S_OK
+            },
+            Err(e) => {
+                log_error!("DllUnregisterServer: Unregistration failed: {:?}", e);
+                E_FAIL
+            },
+        }
+    })
+}
+
+/// Registers for the current user only, under HKEY_CURRENT_USER\Software\Classes, so no administrator rights are needed.
+/// Meant for installers and portable setups that call the export directly rather than through regsvr32.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn DllRegisterServerUser() -> HRESULT {
+    ffi_guard!(HRESULT, {
+        match create_registry_keys(RegistrationScope::User) {
+            Ok(_) => {
+                log_info!("DllRegisterServerUser: Registration succeeded");
+                S_OK
+            },
+            Err(e) => {
+                log_error!("DllRegisterServerUser: Registration failed: {:?}", e);
+                E_FAIL
+            },
+        }
+    })
+}
+
+/// Removes the per-user registration written by DllRegisterServerUser.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn DllUnregisterServerUser() -> HRESULT {
+    ffi_guard!(HRESULT, {
+        match delete_registry_keys(RegistrationScope::User) {
+            Ok(_) => {
+                log_info!("DllUnregisterServerUser: Unregistration succeeded");
+                S_OK
+            },
+            Err(e) => {
+                log_error!("DllUnregisterServerUser: Unregistration failed: {:?}", e);
+                E_FAIL
+            },
+        }
+    })
+}
+
+/// Called by `regsvr32 /i[:cmdline] [/n]`. Pass `/i:user /n` to register for the current user only,
+/// which writes under HKEY_CURRENT_USER\Software\Classes and doesn't need administrator rights.
+/// Any other command line registers for all users, the same as DllRegisterServer.
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "system" fn DllInstall(binstall: BOOL, pszcmdline: PCWSTR) -> HRESULT {
+    ffi_guard!(HRESULT, {
+        let cmdline = if pszcmdline.is_null() {
+            String::new()
+        } else {
+            unsafe { pszcmdline.to_string() }.unwrap_or_default()
+        };
+        let scope = if cmdline.trim().eq_ignore_ascii_case("user") {
+            RegistrationScope::User
+        } else {
+            RegistrationScope::Machine
+        };
+
+        let result = if binstall.as_bool() {
+            create_registry_keys(scope)
+        } else {
+            delete_registry_keys(scope)
+        };
+
+        match result {
             Ok(_) => {
-                log_message("DllUnregisterServer: Unregistration succeeded");
+                log_info!("DllInstall: {} succeeded for scope {:?}", if binstall.as_bool() { "Install" } else { "Uninstall" }, scope);
                 S_OK
             },
             Err(e) => {
-                log_message(&format!("DllUnregisterServer: Unregistration failed: {:?}", e));
+                log_error!("DllInstall: {} failed for scope {:?}: {:?}", if binstall.as_bool() { "Install" } else { "Uninstall" }, scope, e);
                 E_FAIL
             },
         }
     })
 }
 
+#[no_mangle]
+// Re-reads the registry settings, e.g. after a settings tool changed them, without restarting the host process.
+pub extern "system" fn reload_config() -> HRESULT {
+    ffi_guard!(HRESULT, {
+        let new_config = Arc::new(Config::load());
+        let config_lock = CONFIG.get_or_init(|| RwLock::new(Arc::clone(&new_config)));
+        *config_lock.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::clone(&new_config);
+        // The badge image may have changed on disk as well
+        BADGE_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+
+        log_info!("reload_config: Registry settings reloaded");
+        new_config.log_settings();
+        S_OK
+    })
+}
+
+#[no_mangle]
+// Writes e.g. "win_sdr_thumbs 1.9.0 (x86_64, built 2025-01-31 12:00 UTC)" into the caller's UTF-16 buffer, for installers
+// and support tools. Returns the buffer length needed in UTF-16 units including the terminating null. If `buffer` is
+// null or `len` is too small nothing is written, so callers can ask for the size first and call again.
+pub extern "system" fn get_version_info(buffer: *mut u16, len: u32) -> u32 {
+    ffi_guard!(u32, {
+        let version_info = format!("{} {} ({}, built {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), std::env::consts::ARCH, env!("WIN_SDR_THUMBS_BUILD_TIME"));
+        let wide_version_info = to_pcwstr(&version_info);
+        let needed_len = wide_version_info.len() as u32;
+
+        if !buffer.is_null() && len >= needed_len {
+            unsafe { std::ptr::copy_nonoverlapping(wide_version_info.as_ptr(), buffer, wide_version_info.len()) };
+        }
+        needed_len
+    })
+}
+
 #[no_mangle]
 // Simple function that only notifies the shell of file association changes.
 pub extern "system" fn notify_shell_change() -> HRESULT {
@@ -942,51 +2983,239 @@ pub extern "system" fn notify_shell_change() -> HRESULT {
     })
 }
 
+#[no_mangle]
+// C entry point of render_sdr_into_hbitmap for hosts that load the DLL directly. `sdr_name` may be null,
+// but the renderer uses the file name's extension to detect the sample format. Padding and the debug overlay are applied
+// like for thumbnails, see render_sdr_into_hbitmap.
+pub extern "system" fn render_sdr_into_dib(sdr_data: *const u8, sdr_data_len: usize, sdr_name: PCWSTR, target_hbitmap: Gdi::HBITMAP) -> HRESULT {
+    ffi_guard!(HRESULT, {
+        if sdr_data.is_null() || sdr_data_len == 0 {
+            return E_POINTER;
+        }
+        let sdr_name = if sdr_name.is_null() {
+            String::new()
+        } else {
+            match unsafe { sdr_name.to_string() } {
+                Ok(name) => name,
+                Err(_) => return E_INVALIDARG,
+            }
+        };
+        let sdr_data = unsafe { std::slice::from_raw_parts(sdr_data, sdr_data_len) };
+
+        match render_sdr_into_hbitmap(sdr_data, &sdr_name, target_hbitmap) {
+            Ok(()) => S_OK,
+            Err(e) => {
+                log_warn!("render_sdr_into_dib: Render failed: {:?}", e);
+                e.code()
+            }
+        }
+    })
+}
+
 // =================================================================
 
 // -------------- Logger ----------------
-fn log_message(message: &str) {
-    if !ENABLE_DEBUG_LOGGING.load(Ordering::Relaxed) {
+
+/// Severity of a log message, a message is written if its level is at or below the configured log level
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+/// Entry point of the log_* macros. The message is only formatted if it will actually be written.
+/// Error messages always go to the error log, so failures in the field leave a trace even with debug logging off.
+fn log_at_level(level: LogLevel, message: impl FnOnce() -> String) {
+    let config = config();
+    let to_debug_log = level as u32 <= config.log_level;
+    let to_error_log = level == LogLevel::Error;
+    if !to_debug_log && !to_error_log {
         return;
     }
 
-    // get_or_init will only execute the closure ONCE, the very first time it's called.
-    // All subsequent calls will return the cached value instantly.
-    let log_path_option = LOG_FILE_PATH.get_or_init(|| {
-        let known_folder_flags = Shell::KNOWN_FOLDER_FLAG::default(); // Use default flags, no special options
-        let desktop_path_pwstr = match unsafe { SHGetKnownFolderPath(&FOLDERID_Desktop, known_folder_flags, None) } {
-            Ok(path) => path,
-            Err(_) => return None, // Initialization failed, cache 'None'
-        };
+    let message = message();
+    if to_debug_log {
+        if let Some(log_path) = get_debug_log_path() {
+            write_log_line(log_path, level, &message);
+        }
+    }
+    if to_error_log {
+        if let Some(error_log_path) = get_error_log_path() {
+            write_log_line(error_log_path, level, &message);
+        }
+    }
+    // Only problems go to the event log, per-call info and trace lines would flood the system log
+    if config.log_to_event_log && matches!(level, LogLevel::Error | LogLevel::Warn) {
+        write_event_log_entry(level, &message);
+    }
+}
 
-        let desktop_path_guard = CoTaskMemFreeGuard(desktop_path_pwstr);
+// Event source name, registered under HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application
+const EVENT_SOURCE_NAME: PCWSTR = w!("win_sdr_thumbs");
+const EVENT_SOURCE_REGISTRY_PATH: PCWSTR = w!("SYSTEM\\CurrentControlSet\\Services\\EventLog\\Application\\win_sdr_thumbs");
+// EventCreate.exe ships a message table where every id from 1 to 1000 is just "%1", so our text shows up as is
+const EVENT_MESSAGE_FILE: &str = "%SystemRoot%\\System32\\EventCreate.exe";
+const EVENT_ID: u32 = 1;
+
+// Event log handle wrapper, the handle is only used with ReportEventW which is thread safe
+struct EventSourceHandle(HANDLE);
+unsafe impl Send for EventSourceHandle {}
+unsafe impl Sync for EventSourceHandle {}
+
+/// Writes a log line to the Application event log. Failures are ignored like failed file writes.
+/// Must not log, since it runs inside the logger.
+fn write_event_log_entry(level: LogLevel, message: &str) {
+    let event_source = EVENT_SOURCE.get_or_init(|| {
+        unsafe { System::EventLog::RegisterEventSourceW(PCWSTR::null(), EVENT_SOURCE_NAME) }.ok().map(EventSourceHandle)
+    });
+    let Some(event_source) = event_source else {
+        return;
+    };
 
-        let mut path = match unsafe { desktop_path_guard.0.to_string() } {
-            Ok(s) => PathBuf::from(s),
-            Err(_) => return None, // Conversion failed, cache 'None'
-        };
+    let event_type = match level {
+        LogLevel::Error => System::EventLog::EVENTLOG_ERROR_TYPE,
+        LogLevel::Warn => System::EventLog::EVENTLOG_WARNING_TYPE,
+        _ => System::EventLog::EVENTLOG_INFORMATION_TYPE,
+    };
+    let wide_message = to_pcwstr(&format!("[PID: {}] [{}] {}", std::process::id(), level.as_str(), message));
+    let strings = [PCWSTR(wide_message.as_ptr())];
 
-        path.push("win_sdr_thumbs_debug_log.txt");
-        Some(path) // Success! Cache the full path.
-        // --- End of one-time execution block ---
-    });
+    unsafe {
+        let _ = System::EventLog::ReportEventW(event_source.0, event_type, 0, EVENT_ID, None, 0, Some(&strings), None);
+    }
+}
+
+/// Registers the event source used by win_sdr_thumbs_log_to_eventlog. Needs administrator rights, like the rest of
+/// the machine-wide registration. Only the event source name is set up here, writing events stays opt-in via registry.
+fn register_event_source() -> Result<()> {
+    let source_key = RegistryKeyGuard::create_root_key(HKEY_LOCAL_MACHINE, &EVENT_SOURCE_REGISTRY_PATH)?;
+    source_key.set_string_value("EventMessageFile", &expand_environment_strings(EVENT_MESSAGE_FILE))?;
+    source_key.set_dword_value("TypesSupported", (System::EventLog::EVENTLOG_ERROR_TYPE.0 | System::EventLog::EVENTLOG_WARNING_TYPE.0 | System::EventLog::EVENTLOG_INFORMATION_TYPE.0) as u32)?;
+    Ok(())
+}
 
-    // Now, use the cached path.
-    // If initialization failed, log_path_option will be &None, and we'll do nothing.
-    if let Some(log_path) = log_path_option {
-        match std::fs::OpenOptions::new().create(true).append(true).open(log_path) {
-            Ok(mut file) => {
-                let pid = std::process::id();
-                let tid = std::thread::current().id();
-                let time_str = get_formatted_time_string_win_api();
+/// Removes the event source registered by register_event_source. A missing key counts as success.
+fn unregister_event_source() -> Result<()> {
+    let result = unsafe { RegDeleteKeyExW(HKEY_LOCAL_MACHINE, EVENT_SOURCE_REGISTRY_PATH, WRITE_FLAGS.0, Some(0)) };
+    if result != ERROR_SUCCESS && result != ERROR_FILE_NOT_FOUND {
+        return Err(Error::new(result.into(), "Event source key deletion failed"));
+    }
+    Ok(())
+}
 
-                let _ = writeln!(file, "[PID: {} | TID: {:?}] [{}] {}", pid, tid, time_str, message);
+fn get_debug_log_path() -> Option<&'static PathBuf> {
+    // get_or_init will only execute the closure ONCE, the very first time it's called.
+    // All subsequent calls will return the cached value instantly.
+    LOG_FILE_PATH.get_or_init(|| {
+        // A folder set in win_sdr_thumbs_log_path wins if it can be written to. It's only needed once per process,
+        // so it is read here rather than kept in Config. The registry readers don't log, so this can't re-enter.
+        let custom_log_dir = read_sdr_registry_string("win_sdr_thumbs_log_path")
+            .map(|dir| PathBuf::from(expand_environment_strings(&dir)))
+            .filter(|dir| !dir.as_os_str().is_empty() && std::fs::create_dir_all(dir).is_ok())
+            .filter(|dir| std::fs::OpenOptions::new().create(true).append(true).open(dir.join("win_sdr_thumbs_debug_log.txt")).is_ok());
+
+        // Otherwise try each default folder in order, only giving up if none of them is usable. The default moved off
+        // the Desktop to LocalAppData with the log rotation, so an unset or invalid folder falls back to that as well.
+        let log_dir = custom_log_dir.or_else(|| get_known_folder_path(&FOLDERID_LocalAppData)
+            .map(|mut dir| {
+                dir.push("win_sdr_thumbs");
+                dir.push("logs");
+                dir
+            })
+            .filter(|dir| std::fs::create_dir_all(dir).is_ok()))
+            .or_else(|| Some(std::env::temp_dir()).filter(|dir| dir.is_dir()));
+
+        match log_dir {
+            Some(mut path) => {
+                path.push("win_sdr_thumbs_debug_log.txt");
+                Some(path) // Success! Cache the full path.
             }
-            Err(_) => {
-                // Opening the file failed.
+            None => {
+                // We can't log that logging failed, so tell an attached debugger instead. This only runs once.
+                unsafe { OutputDebugStringW(w!("win_sdr_thumbs: Debug logging is enabled but no log folder was usable (%LOCALAPPDATA%\\win_sdr_thumbs\\logs, %TEMP%)\n")) };
+                None
             }
         }
+        // --- End of one-time execution block ---
+    }).as_ref()
+}
+
+fn get_error_log_path() -> Option<&'static PathBuf> {
+    // The error log is always on, so keep it out of sight in %LOCALAPPDATA%\win_sdr_thumbs rather than on the Desktop
+    ERROR_LOG_FILE_PATH.get_or_init(|| {
+        let mut path = get_known_folder_path(&FOLDERID_LocalAppData)
+            .map(|mut dir| {
+                dir.push("win_sdr_thumbs");
+                dir
+            })
+            .filter(|dir| std::fs::create_dir_all(dir).is_ok())
+            .or_else(|| Some(std::env::temp_dir()).filter(|dir| dir.is_dir()))?;
+        path.push("win_sdr_thumbs_error_log.txt");
+        Some(path)
+    }).as_ref()
+}
+
+fn write_log_line(log_path: &Path, level: LogLevel, message: &str) {
+    // Several Explorer threads log at once, a poisoned lock only means another thread panicked mid-write
+    let _write_lock = LOG_WRITE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    rotate_log_file_if_needed(log_path);
+
+    match std::fs::OpenOptions::new().create(true).append(true).open(log_path) {
+        Ok(mut file) => {
+            let pid = std::process::id();
+            let tid = std::thread::current().id();
+            let time_str = get_formatted_time_string_win_api();
+
+            // Format the whole line first and write it in one call. Appends of a single write don't
+            // interleave with other processes that log to the same file (e.g. Explorer and prevhost).
+            let line = format!("[PID: {} | TID: {:?}] [{}] [{}] {}\r\n", pid, tid, time_str, level.as_str(), message);
+            let _ = file.write_all(line.as_bytes());
+        }
+        Err(_) => {
+            // Opening the file failed.
+        }
+    }
+}
+
+/// Rolls the log file over to .1 once it exceeds the size cap, shifting older files up to LOG_ROTATION_COUNT.
+/// Another process may rotate the same file at the same moment, so failed renames are ignored.
+/// Must not log, since it runs inside the logger.
+fn rotate_log_file_if_needed(log_path: &Path) {
+    let log_size = match std::fs::metadata(log_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return, // No log file yet
+    };
+    if log_size < config().log_max_size {
+        return;
+    }
+
+    let rotated_path = |index: u32| {
+        let mut file_name = log_path.as_os_str().to_owned();
+        file_name.push(format!(".{}", index));
+        PathBuf::from(file_name)
+    };
+
+    // Drop the oldest file, then shift each remaining one up by one, ending with the current file to .1
+    let _ = std::fs::remove_file(rotated_path(LOG_ROTATION_COUNT));
+    for index in (1..LOG_ROTATION_COUNT).rev() {
+        let _ = std::fs::rename(rotated_path(index), rotated_path(index + 1));
     }
+    let _ = std::fs::rename(log_path, rotated_path(1));
 }
 
 fn get_formatted_time_string_win_api() -> String {
@@ -1014,6 +3243,192 @@ fn get_formatted_time_string_win_api() -> String {
     }
 }
 
-// fn log_message(message: &str) {
-//     println!("{}", message);
-// }
+/// Expands environment variables such as %TEMP% in a path. Returns the input unchanged if expansion fails.
+/// Must not log, since the logger itself uses this to find its file.
+fn expand_environment_strings(value: &str) -> String {
+    let wide_value = to_pcwstr(value);
+    let required_len = unsafe { System::Environment::ExpandEnvironmentStringsW(PCWSTR(wide_value.as_ptr()), None) };
+    if required_len == 0 {
+        return value.to_string();
+    }
+
+    let mut buffer = vec![0u16; required_len as usize];
+    let written_len = unsafe { System::Environment::ExpandEnvironmentStringsW(PCWSTR(wide_value.as_ptr()), Some(&mut buffer)) };
+    if written_len == 0 || written_len > required_len {
+        return value.to_string();
+    }
+    String::from_utf16_lossy(&buffer[..written_len as usize - 1]) // -1 to remove null terminator
+}
+
+/// Looks up a known folder such as the Desktop. Returns None if the lookup or the string conversion fails.
+/// Must not log, since the logger itself uses this to find its file.
+fn get_known_folder_path(folder_id: &GUID) -> Option<PathBuf> {
+    let known_folder_flags = Shell::KNOWN_FOLDER_FLAG::default(); // Use default flags, no special options
+    let folder_path_pwstr = unsafe { SHGetKnownFolderPath(folder_id, known_folder_flags, None) }.ok()?;
+    let folder_path_guard = CoTaskMemFreeGuard(folder_path_pwstr);
+
+    match unsafe { folder_path_guard.0.to_string() } {
+        Ok(s) => Some(PathBuf::from(s)),
+        Err(_) => None,
+    }
+}
+
+// =================================================================
+//                            Tests
+// =================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Not SDR data, and the extension doesn't name a sample format, so the renderer can't draw it
+    const INVALID_SDR_DATA: &[u8] = b"This is not a recording of I/Q samples";
+    const INVALID_SDR_NAME: &str = "not_a_recording.txt";
+
+    #[test]
+    fn render_sdr_to_hbitmap_returns_an_error_for_invalid_data() {
+        match render_sdr_to_hbitmap(INVALID_SDR_DATA, INVALID_SDR_NAME, 64, 64) {
+            Ok(hbitmap) => {
+                let _ = HBitmapGuard::new(hbitmap);
+                panic!("Invalid data rendered to a bitmap instead of returning an error");
+            }
+            Err(e) => assert_eq!(e.code(), HRESULT::from_win32(ERROR_INVALID_DATA.0)),
+        }
+    }
+
+    #[test]
+    fn render_sdr_to_png_bytes_returns_an_error_for_invalid_data() {
+        let error = render_sdr_to_png_bytes(INVALID_SDR_DATA, INVALID_SDR_NAME, 64, 64).unwrap_err();
+        assert_eq!(error.code(), HRESULT::from_win32(ERROR_INVALID_DATA.0));
+    }
+
+    #[test]
+    fn render_sdr_to_hbitmap_rejects_a_zero_size() {
+        let error = render_sdr_to_hbitmap(INVALID_SDR_DATA, "empty.cu8", 0, 64).unwrap_err();
+        assert_eq!(error.code(), E_INVALIDARG);
+    }
+
+    #[test]
+    fn reject_if_too_large_accepts_sizes_up_to_the_cap() {
+        let max_file_size = config().max_file_size;
+        assert!(reject_if_too_large(max_file_size - 1).is_ok());
+        assert!(reject_if_too_large(max_file_size).is_ok());
+    }
+
+    #[test]
+    fn reject_if_too_large_rejects_one_byte_over_the_cap() {
+        let error = reject_if_too_large(config().max_file_size + 1).unwrap_err();
+        assert_eq!(error.code(), HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0));
+    }
+
+    // A read-only stream of zeros whose Stat reports `reported_size` while Read serves `actual_size` bytes,
+    // to check that read_stream_data trusts neither
+    #[implement(Com::IStream)]
+    struct FakeStream {
+        reported_size: u64,
+        actual_size: u64,
+        position: std::sync::atomic::AtomicU64,
+    }
+
+    impl FakeStream {
+        fn new_stream(reported_size: u64, actual_size: u64) -> Com::IStream {
+            FakeStream { reported_size, actual_size, position: std::sync::atomic::AtomicU64::new(0) }.into()
+        }
+    }
+
+    impl Com::ISequentialStream_Impl for FakeStream_Impl {
+        fn Read(&self, pv: *mut std::ffi::c_void, cb: u32, pcbread: *mut u32) -> HRESULT {
+            let position = self.position.load(Ordering::Relaxed);
+            let bytes_read = (cb as u64).min(self.actual_size - position) as u32;
+            unsafe {
+                std::ptr::write_bytes(pv as *mut u8, 0, bytes_read as usize);
+                if !pcbread.is_null() {
+                    *pcbread = bytes_read;
+                }
+            }
+            self.position.store(position + bytes_read as u64, Ordering::Relaxed);
+            if bytes_read < cb { S_FALSE } else { S_OK }
+        }
+
+        fn Write(&self, _pv: *const std::ffi::c_void, _cb: u32, _pcbwritten: *mut u32) -> HRESULT {
+            STG_E_ACCESSDENIED
+        }
+    }
+
+    impl Com::IStream_Impl for FakeStream_Impl {
+        fn Seek(&self, _dlibmove: i64, _dworigin: Com::STREAM_SEEK, _plibnewposition: *mut u64) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn SetSize(&self, _libnewsize: u64) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn CopyTo(&self, _pstm: Ref<'_, Com::IStream>, _cb: u64, _pcbread: *mut u64, _pcbwritten: *mut u64) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn Commit(&self, _grfcommitflags: &Com::STGC) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn Revert(&self) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn LockRegion(&self, _liboffset: u64, _cb: u64, _dwlocktype: &Com::LOCKTYPE) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn UnlockRegion(&self, _liboffset: u64, _cb: u64, _dwlocktype: u32) -> Result<()> {
+            Err(E_NOTIMPL.into())
+        }
+
+        fn Stat(&self, pstatstg: *mut Com::STATSTG, _grfstatflag: &Com::STATFLAG) -> Result<()> {
+            // No name, like streams that don't belong to a file
+            unsafe { *pstatstg = Com::STATSTG { cbSize: self.reported_size, ..Default::default() } };
+            Ok(())
+        }
+
+        fn Clone(&self) -> Result<Com::IStream> {
+            Err(E_NOTIMPL.into())
+        }
+    }
+
+    #[test]
+    fn read_stream_data_reads_a_stream_within_the_cap() {
+        let stream = FakeStream::new_stream(1000, 1000);
+        let stream_data = read_stream_data(&stream).unwrap();
+        assert_eq!(stream_data.stream_bytes.len(), 1000);
+        assert!(stream_data.stream_name.is_empty());
+    }
+
+    #[test]
+    fn read_stream_data_rejects_an_oversized_reported_size() {
+        // Nothing would be read anyway, the reported size alone has to be enough to reject it
+        let stream = FakeStream::new_stream(config().max_file_size + 1, 0);
+        let error = read_stream_data(&stream).err().unwrap();
+        assert_eq!(error.code(), HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0));
+    }
+
+    #[test]
+    fn read_stream_data_rejects_a_stream_larger_than_reported() {
+        let stream = FakeStream::new_stream(1000, config().max_file_size + 1);
+        let error = read_stream_data(&stream).err().unwrap();
+        assert_eq!(error.code(), HRESULT::from_win32(ERROR_FILE_TOO_LARGE.0));
+    }
+
+    #[test]
+    fn fallback_cross_covers_both_diagonals() {
+        let size = 16;
+        let mut pixels = vec![0u32; size * size];
+        draw_fallback_cross(&mut pixels, size as u32);
+
+        for i in 0..size {
+            assert_eq!(pixels[i * size + i], 0xFFFF0000);
+            assert_eq!(pixels[i * size + (size - 1 - i)], 0xFFFF0000);
+        }
+        // Off the diagonals the background stays transparent
+        assert_eq!(pixels[size / 2], 0);
+    }
+}
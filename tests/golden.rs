@@ -0,0 +1,141 @@
+// Renders the recordings in tests/golden and compares them with the committed reference PNGs next to them,
+// e.g. g001_433.92M_250k.cu8 against g001_433.92M_250k.cu8.png.
+//
+// Set UPDATE_GOLDEN=1 to write the current output as the new references instead, then review and commit the PNGs.
+// Everything renders with the default settings, so the registry settings under HKEY_CLASSES_ROOT\.cu8 (padding,
+// badge, ...) on the machine running the tests don't change the output.
+
+#![cfg(windows)]
+
+use std::path::{Path, PathBuf};
+use windows::Win32::Graphics::Imaging::{
+    CLSID_WICImagingFactory, GUID_WICPixelFormat32bppBGRA, IWICImagingFactory, WICBitmapDitherTypeNone,
+    WICBitmapPaletteTypeCustom, WICDecodeMetadataCacheOnDemand,
+};
+use windows::Win32::System::Com::{CoCreateInstance, CoInitializeEx, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED};
+
+use win_sdr_thumbs::{render_sdr_to_png_bytes, use_default_settings};
+
+// Size the references are rendered at, small enough to keep the committed PNGs tiny
+const GOLDEN_SIZE: u32 = 128;
+// Largest difference allowed in any channel of a pixel, absorbs rounding differences between machines
+const CHANNEL_TOLERANCE: u8 = 2;
+// Share of pixels that may exceed the tolerance before a render counts as changed
+const MAX_DIFFERING_PIXEL_RATIO: f64 = 0.001;
+
+const GOLDEN_FIXTURES: &[&str] = &[
+    // On-off keyed bursts of a tone, 8-bit unsigned samples
+    "g001_433.92M_250k.cu8",
+    // A linear chirp across the band, 16-bit signed samples
+    "g002_868M_1000k.cs16",
+];
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+/// Decodes a PNG into straight-alpha BGRA pixels with WIC, returning the width, height and pixels
+fn decode_png(png_bytes: &[u8]) -> windows::core::Result<(u32, u32, Vec<u8>)> {
+    unsafe {
+        let factory: IWICImagingFactory = CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)?;
+        let stream = factory.CreateStream()?;
+        stream.InitializeFromMemory(png_bytes)?;
+
+        let decoder = factory.CreateDecoderFromStream(&stream, std::ptr::null(), WICDecodeMetadataCacheOnDemand)?;
+        let frame = decoder.GetFrame(0)?;
+        let (mut width, mut height) = (0, 0);
+        frame.GetSize(&mut width, &mut height)?;
+
+        let converter = factory.CreateFormatConverter()?;
+        converter.Initialize(&frame, &GUID_WICPixelFormat32bppBGRA, WICBitmapDitherTypeNone, None, 0.0, WICBitmapPaletteTypeCustom)?;
+
+        let mut pixels = vec![0u8; width as usize * height as usize * 4];
+        converter.CopyPixels(std::ptr::null(), width * 4, &mut pixels)?;
+        Ok((width, height, pixels))
+    }
+}
+
+/// Compares two PNGs pixel by pixel, returning a description of the difference if they don't match
+fn compare_png(actual_png: &[u8], expected_png: &[u8]) -> Result<(), String> {
+    let (actual_width, actual_height, actual_pixels) = decode_png(actual_png).map_err(|e| format!("could not decode render: {}", e))?;
+    let (expected_width, expected_height, expected_pixels) = decode_png(expected_png).map_err(|e| format!("could not decode reference: {}", e))?;
+    if (actual_width, actual_height) != (expected_width, expected_height) {
+        return Err(format!("size is {}x{}, reference is {}x{}", actual_width, actual_height, expected_width, expected_height));
+    }
+
+    let mut differing_pixels = 0usize;
+    let mut max_difference = 0u8;
+    for (actual, expected) in actual_pixels.chunks_exact(4).zip(expected_pixels.chunks_exact(4)) {
+        let difference = actual.iter().zip(expected).map(|(a, e)| a.abs_diff(*e)).max().unwrap_or(0);
+        max_difference = max_difference.max(difference);
+        if difference > CHANNEL_TOLERANCE {
+            differing_pixels += 1;
+        }
+    }
+
+    let pixel_count = (actual_width * actual_height) as usize;
+    if differing_pixels as f64 > pixel_count as f64 * MAX_DIFFERING_PIXEL_RATIO {
+        return Err(format!("{} of {} pixels differ by more than {} (largest difference {})", differing_pixels, pixel_count, CHANNEL_TOLERANCE, max_difference));
+    }
+    Ok(())
+}
+
+/// Sets up what every test here needs: COM for WIC, which Explorer would normally have set up, and the default settings
+fn init() {
+    let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    use_default_settings();
+}
+
+#[test]
+#[ignore = "no reference PNGs committed yet, create them with UPDATE_GOLDEN=1 and remove this"]
+fn renders_match_golden_references() {
+    init();
+    let update_golden = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    let mut failures = Vec::new();
+    for fixture in GOLDEN_FIXTURES {
+        let fixture_path = golden_dir().join(fixture);
+        let reference_path = golden_dir().join(format!("{}.png", fixture));
+
+        let sdr_data = std::fs::read(&fixture_path).unwrap_or_else(|e| panic!("could not read {}: {}", fixture_path.display(), e));
+        let actual_png = match render_sdr_to_png_bytes(&sdr_data, fixture, GOLDEN_SIZE, GOLDEN_SIZE) {
+            Ok(png_bytes) => png_bytes,
+            Err(e) => {
+                failures.push(format!("{}: render failed: {}", fixture, e));
+                continue;
+            }
+        };
+
+        if update_golden {
+            std::fs::write(&reference_path, &actual_png).unwrap_or_else(|e| panic!("could not write {}: {}", reference_path.display(), e));
+            println!("Updated {}", reference_path.display());
+            continue;
+        }
+
+        match std::fs::read(&reference_path) {
+            Ok(expected_png) => {
+                if let Err(difference) = compare_png(&actual_png, &expected_png) {
+                    failures.push(format!("{}: {}", fixture, difference));
+                }
+            }
+            Err(e) => failures.push(format!("{}: no reference at {} ({}), run with UPDATE_GOLDEN=1 to create it", fixture, reference_path.display(), e)),
+        }
+    }
+
+    assert!(failures.is_empty(), "Renders differ from the golden references:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn non_square_render_has_the_requested_size() {
+    init();
+    let fixture = GOLDEN_FIXTURES[0];
+    let fixture_path = golden_dir().join(fixture);
+    let sdr_data = std::fs::read(&fixture_path).unwrap_or_else(|e| panic!("could not read {}: {}", fixture_path.display(), e));
+
+    // Wider than tall, so swapped or squared dimensions anywhere in the pipeline show up as a size mismatch
+    let png_bytes = render_sdr_to_png_bytes(&sdr_data, fixture, 200, 100).expect("render failed");
+    let (width, height, pixels) = decode_png(&png_bytes).expect("could not decode render");
+    assert_eq!((width, height), (200, 100));
+    assert_eq!(pixels.len(), 200 * 100 * 4);
+    assert!(pixels.chunks_exact(4).any(|pixel| pixel[3] != 0), "Render is completely transparent");
+}